@@ -0,0 +1,430 @@
+use anyhow::{anyhow, Context, Result};
+use astronote_core::schedulers::sm2::SuperMemo2;
+use astronote_core::Note;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Notes and scheduling state read from an Anki collection.
+pub struct Collection {
+    /// creation time of the collection (unix time); review due dates count days from it
+    pub created: i64,
+    pub notes: Vec<AnkiNote>,
+}
+
+pub struct AnkiNote {
+    pub id: i64,
+    /// (field name, content)
+    pub fields: Vec<(String, String)>,
+    /// scheduling state of the first card of the note
+    pub card: Option<Card>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Card {
+    /// 0: new, 1: learning, 2: review, 3: relearning
+    pub card_type: i64,
+    /// 0: new, 1: learning, 2: review, 3: day learning; negative while suspended or buried
+    pub queue: i64,
+    pub due: i64,
+    /// interval in days (negative while learning: seconds)
+    pub ivl: i64,
+    /// ease in permille
+    pub factor: i64,
+    pub reps: i64,
+    pub lapses: i64,
+}
+
+/// Read an Anki collection (`collection.anki2`) or a deck package (`.apkg`).
+pub fn read_collection(path: &Path) -> Result<Collection> {
+    let is_package = path.extension().is_some_and(|ext| ext == "apkg");
+    if !is_package {
+        return read_sqlite(path);
+    }
+    // a package is a zip archive containing the collection
+    let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("Failed to read {path:?} as an Anki package"))?;
+    let name = ["collection.anki21", "collection.anki2"]
+        .into_iter()
+        .find(|name| archive.by_name(name).is_ok())
+        .ok_or(anyhow!(
+            "No collection found in {path:?}; packages of recent Anki versions must be exported with \"Support older Anki versions\""
+        ))?;
+    let mut collection_file = tempfile::NamedTempFile::new()
+        .with_context(|| "Failed to create temporary file for the collection")?;
+    io::copy(&mut archive.by_name(name)?, &mut collection_file)
+        .with_context(|| format!("Failed to extract {name} from {path:?}"))?;
+    read_sqlite(collection_file.path())
+}
+
+fn read_sqlite(path: &Path) -> Result<Collection> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open Anki collection: {path:?}"))?;
+    let created: i64 = connection
+        .query_row("SELECT crt FROM col", [], |row| row.get(0))
+        .with_context(|| "Failed to read collection creation time")?;
+    let field_names = read_field_names(&connection)?;
+
+    let mut cards: HashMap<i64, Card> = HashMap::new();
+    let mut statement = connection.prepare(
+        "SELECT nid, type, queue, due, ivl, factor, reps, lapses FROM cards ORDER BY nid, ord",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let card = Card {
+            card_type: row.get(1)?,
+            queue: row.get(2)?,
+            due: row.get(3)?,
+            ivl: row.get(4)?,
+            factor: row.get(5)?,
+            reps: row.get(6)?,
+            lapses: row.get(7)?,
+        };
+        Ok((row.get::<_, i64>(0)?, card))
+    })?;
+    for row in rows {
+        let (note_id, card) = row.with_context(|| "Failed to read card")?;
+        cards.entry(note_id).or_insert(card);
+    }
+
+    let mut statement = connection.prepare("SELECT id, mid, flds FROM notes ORDER BY id")?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut notes = vec![];
+    for row in rows {
+        let (id, model_id, fields) = row.with_context(|| "Failed to read note")?;
+        let names = field_names.get(&model_id);
+        // fields are separated by the unit separator
+        let fields = fields
+            .split('\x1f')
+            .enumerate()
+            .map(|(i, content)| {
+                let name = names
+                    .and_then(|names| names.get(i).cloned())
+                    .unwrap_or_else(|| format!("Field{}", i + 1));
+                (name, content.to_string())
+            })
+            .collect();
+        notes.push(AnkiNote {
+            id,
+            fields,
+            card: cards.remove(&id),
+        });
+    }
+    Ok(Collection { created, notes })
+}
+
+// Field names of each note type.
+// Recent collections have a `fields` table; older ones keep note types as JSON in `col.models`.
+fn read_field_names(connection: &Connection) -> Result<HashMap<i64, Vec<String>>> {
+    let mut field_names: HashMap<i64, Vec<String>> = HashMap::new();
+    if let Ok(mut statement) =
+        connection.prepare("SELECT ntid, name FROM fields ORDER BY ntid, ord")
+    {
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (model_id, name): (i64, String) = row?;
+            field_names.entry(model_id).or_default().push(name);
+        }
+        return Ok(field_names);
+    }
+    let models: String = connection
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .with_context(|| "Failed to read note types")?;
+    let models: serde_json::Value =
+        serde_json::from_str(&models).with_context(|| "Failed to parse note types")?;
+    for (model_id, model) in models.as_object().into_iter().flatten() {
+        let mut fields = model["flds"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|field| {
+                let ord = field["ord"].as_i64().unwrap_or_default();
+                let name = field["name"].as_str().unwrap_or_default().to_string();
+                (ord, name)
+            })
+            .collect::<Vec<_>>();
+        fields.sort();
+        let model_id = model_id
+            .parse()
+            .with_context(|| format!("Invalid note type id: {model_id}"))?;
+        field_names.insert(model_id, fields.into_iter().map(|(_, name)| name).collect());
+    }
+    Ok(field_names)
+}
+
+impl Collection {
+    /// Check that `template` and `name_field` only refer to fields of the notes.
+    pub fn check_fields(&self, template: Option<&str>, name_field: Option<&str>) -> Result<()> {
+        let names = self
+            .notes
+            .iter()
+            .flat_map(|note| note.fields.iter().map(|(name, _)| name.as_str()))
+            .collect::<HashSet<_>>();
+        let placeholders = template
+            .into_iter()
+            .flat_map(|template| template.split("{{").skip(1))
+            .filter_map(|rest| rest.split_once("}}").map(|(name, _)| name));
+        for name in placeholders.chain(name_field) {
+            if !names.contains(name) {
+                let mut names = names.into_iter().collect::<Vec<_>>();
+                names.sort();
+                return Err(anyhow!(
+                    "No field named `{name}` in the collection; its fields are {names:?}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AnkiNote {
+    /// Render the note as markdown.
+    ///
+    /// `{{Field}}` in `template` is replaced with the content of the field.
+    /// Without a template, every field is written under its own heading.
+    pub fn render(&self, template: Option<&str>) -> String {
+        match template {
+            Some(template) => {
+                self.fields
+                    .iter()
+                    .fold(template.replace("\\n", "\n"), |text, (name, content)| {
+                        text.replace(&format!("{{{{{name}}}}}"), &html_to_text(content))
+                    })
+            }
+            None => self
+                .fields
+                .iter()
+                .map(|(name, content)| format!("## {}\n\n{}\n", name, html_to_text(content)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// File name (without extension) derived from `name_field`, or the first field.
+    pub fn file_stem(&self, name_field: Option<&str>) -> String {
+        let content = match name_field {
+            Some(name_field) => self
+                .fields
+                .iter()
+                .find(|(name, _)| name == name_field)
+                .map(|(_, content)| content.as_str()),
+            None => self.fields.first().map(|(_, content)| content.as_str()),
+        };
+        let stem = sanitize_file_stem(&strip_html_tags(content.unwrap_or_default()));
+        if stem.is_empty() {
+            format!("anki-{}", self.id)
+        } else {
+            stem
+        }
+    }
+}
+
+impl Card {
+    /// Convert Anki's ease, interval and repetitions into SuperMemo2 state.
+    pub fn to_supermemo2(&self) -> SuperMemo2 {
+        // ease is stored in permille (2500 = 2.5); new cards have no ease yet
+        let easiness_factor = match self.factor {
+            0 => 2.5,
+            factor => (factor as f64 / 1000.0).max(1.3),
+        };
+        match self.card_type {
+            // review cards continue growing their interval by the easiness factor
+            2 if self.ivl >= 6 => {
+                SuperMemo2::new((self.reps - self.lapses).max(2), self.ivl, easiness_factor)
+            }
+            2 => SuperMemo2::new(1, self.ivl.max(1), easiness_factor),
+            // new and (re)learning cards start over
+            _ => SuperMemo2::new(0, 0, easiness_factor),
+        }
+    }
+
+    /// Due date of the card. New cards are due `now`.
+    pub fn next_datetime(
+        &self,
+        collection_created: i64,
+        now: chrono::NaiveDateTime,
+    ) -> chrono::NaiveDateTime {
+        let from_timestamp = |timestamp: i64| {
+            chrono::DateTime::from_timestamp(timestamp, 0)
+                .map(|datetime| datetime.with_timezone(&chrono::Local).naive_local())
+        };
+        let due_day = || {
+            from_timestamp(collection_created)
+                .and_then(|created| created.checked_add_signed(chrono::Duration::days(self.due)))
+        };
+        let due = match (self.card_type, self.queue) {
+            (0, _) => None,
+            // review and day-learning cards are due a number of days after the collection was created
+            (_, 2) | (_, 3) => due_day(),
+            // learning cards within a day are due at a unix time
+            (1, _) | (_, 1) => from_timestamp(self.due),
+            _ => due_day(),
+        };
+        due.unwrap_or(now)
+    }
+}
+
+/// Write each note of the collection as a markdown file into `output_dir` (relative to `root`)
+/// and create astronote notes carrying over the Anki schedule.
+///
+/// Existing files are not overwritten; their count is returned along with the notes.
+pub fn export_notes(
+    collection: &Collection,
+    root: &Path,
+    output_dir: &Path,
+    template: Option<&str>,
+    name_field: Option<&str>,
+) -> Result<(Vec<Note>, usize)> {
+    let now = chrono::Local::now().naive_local();
+    let mut used_stems = HashSet::new();
+    let mut notes = vec![];
+    let mut skipped = 0;
+    for anki_note in collection.notes.iter() {
+        let stem = anki_note.file_stem(name_field);
+        let stem = (1..)
+            .map(|i| match i {
+                1 => stem.clone(),
+                i => format!("{stem}-{i}"),
+            })
+            .find(|stem| !used_stems.contains(stem))
+            .expect("infinite candidates");
+        used_stems.insert(stem.clone());
+        let relative_path: PathBuf = output_dir.join(format!("{stem}.md"));
+        let absolute_path = root.join(&relative_path);
+        if absolute_path.exists() {
+            skipped += 1;
+            continue;
+        }
+        std::fs::write(&absolute_path, anki_note.render(template))
+            .with_context(|| format!("Failed to write {absolute_path:?}"))?;
+
+        let (scheduler, next_datetime) = match &anki_note.card {
+            Some(card) => (
+                card.to_supermemo2(),
+                card.next_datetime(collection.created, now),
+            ),
+            None => (SuperMemo2::default(), now),
+        };
+        let mut note = Note::new(&relative_path, &next_datetime, Box::new(scheduler));
+        note.refresh_fingerprint(&absolute_path)?;
+        notes.push(note);
+    }
+    Ok((notes, skipped))
+}
+
+fn html_to_text(html: &str) -> String {
+    ["<br>", "<br/>", "<br />", "<div>", "</div>"]
+        .iter()
+        .fold(html.to_string(), |text, tag| {
+            let replacement = if *tag == "<div>" { "" } else { "\n" };
+            text.replace(tag, replacement)
+        })
+        .replace("&nbsp;", " ")
+        .trim()
+        .to_string()
+}
+
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+}
+
+fn sanitize_file_stem(text: &str) -> String {
+    let stem = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    stem.chars().take(64).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn create_collection(path: &Path) {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                CREATE TABLE col (crt INTEGER, models TEXT);
+                CREATE TABLE notes (id INTEGER, mid INTEGER, flds TEXT);
+                CREATE TABLE cards (
+                    nid INTEGER, ord INTEGER, type INTEGER, queue INTEGER, due INTEGER,
+                    ivl INTEGER, factor INTEGER, reps INTEGER, lapses INTEGER
+                );
+                INSERT INTO col VALUES (
+                    1700000000,
+                    '{"1": {"flds": [{"name": "Back", "ord": 1}, {"name": "Front", "ord": 0}]}}'
+                );
+                INSERT INTO notes VALUES (10, 1, 'What is <b>Rust</b>?' || char(31) || 'A language');
+                INSERT INTO notes VALUES (11, 1, 'New card' || char(31) || 'Not seen yet');
+                INSERT INTO notes VALUES (12, 1, 'Learning' || char(31) || 'Over days');
+                INSERT INTO cards VALUES (10, 0, 2, 2, 100, 20, 2300, 7, 1);
+                INSERT INTO cards VALUES (11, 0, 0, 0, 5, 0, 0, 0, 0);
+                INSERT INTO cards VALUES (12, 0, 1, 3, 101, 1, 2500, 2, 0);
+                "#,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn import_collection() {
+        let dir = TempDir::new("anki").unwrap();
+        let collection_path = dir.path().join("collection.anki2");
+        create_collection(&collection_path);
+        let collection = read_collection(&collection_path).unwrap();
+        assert_eq!(collection.notes.len(), 3);
+        assert!(collection
+            .check_fields(Some("# {{Front}}\\n\\n{{Back}}"), Some("Front"))
+            .is_ok());
+        assert!(collection.check_fields(Some("{{Title}}"), None).is_err());
+        assert!(collection.check_fields(None, Some("Title")).is_err());
+
+        let note = &collection.notes[0];
+        assert_eq!(
+            note.fields[0],
+            ("Front".to_string(), "What is <b>Rust</b>?".to_string())
+        );
+        assert_eq!(note.file_stem(None), "What-is-Rust");
+        assert_eq!(
+            note.render(Some("# {{Front}}\\n\\n{{Back}}")),
+            "# What is <b>Rust</b>?\n\nA language"
+        );
+        let sm2 = note.card.as_ref().unwrap().to_supermemo2();
+        assert_eq!((sm2.counter, sm2.interval), (6, 20));
+        assert_eq!(sm2.easiness_factor, 2.3);
+
+        std::fs::create_dir(dir.path().join("anki")).unwrap();
+        let (notes, skipped) =
+            export_notes(&collection, dir.path(), Path::new("anki"), None, None).unwrap();
+        assert_eq!((notes.len(), skipped), (3, 0));
+        assert_eq!(notes[0].relative_path, Path::new("anki/What-is-Rust.md"));
+        assert!(notes[0].next_datetime < notes[1].next_datetime);
+        // day learning cards count days like review cards
+        let days = notes[2].next_datetime - notes[0].next_datetime;
+        assert_eq!(days, chrono::Duration::days(1));
+        // importing again does not overwrite the files
+        let (notes, skipped) =
+            export_notes(&collection, dir.path(), Path::new("anki"), None, None).unwrap();
+        assert_eq!((notes.len(), skipped), (0, 3));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::overview::ListOrder;
+use crate::table::TableFormat;
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use std::{io::Write, path::PathBuf};
@@ -11,6 +13,9 @@ pub struct CommandParser {
     pub subcommand: Commands,
     /// path to database (default: ./.astronote.db))
     pub database_path: Option<PathBuf>,
+    /// Collection to work on (default: the notes under `root`).
+    #[arg(short, long, global = true, value_name = "NAME")]
+    pub collection: Option<String>,
 }
 
 impl CommandParser {
@@ -37,6 +42,36 @@ pub enum Commands {
         #[arg(value_name = "FILE")]
         #[arg(num_args = 1.., value_delimiter = ' ')]
         files: Vec<PathBuf>,
+        /// Add files under directories, except those ignored by `.gitignore` or `.astronoteignore`.
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only add files matching this glob, relative to `root` (e.g. `**/*.md`; `*` stays within a directory, `**` crosses directories); can be repeated.
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Skip files matching this glob, relative to `root` (same syntax as `--include`); can be repeated.
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+    },
+    /// Stop reviewing files; the files themselves are kept.
+    Remove {
+        /// Files, directories or glob patterns (e.g. `'notes/**/*.md'`; `*` stays within a directory, `**` crosses directories) to remove.
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+        /// Also remove notes whose files no longer exist.
+        #[arg(long)]
+        missing: bool,
+        /// Only show which notes would be removed.
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation.
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Add files matching `auto_add` in config which are not tracked yet, and report tracked notes whose files are gone.
+    Scan {
+        /// Ask before adding each file.
+        #[arg(short, long)]
+        interactive: bool,
     },
     /// Start reviewing.
     Review {
@@ -47,6 +82,143 @@ pub enum Commands {
         #[arg(short, long)]
         ignore_schedule: Option<bool>,
     },
+    /// Show the next note to review, for scripts and editor plugins.
+    Next {
+        /// Print the note as JSON (`null` if there is none).
+        #[arg(long)]
+        json: bool,
+        /// Ignore schedule; pick the earliest due note even if it is not due yet.
+        #[arg(short, long)]
+        ignore_schedule: bool,
+    },
+    /// Record the quality of answer for a note without prompting, for scripts and editor plugins.
+    Grade {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        /// Quality of answer from 0 (complete blackout) to 6 (perfect over multiple sessions).
+        #[arg(value_name = "GRADE", value_parser = clap::value_parser!(u8).range(0..=6))]
+        grade: u8,
+        /// Print the graded note as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List notes with their due dates and scheduler parameters.
+    List {
+        /// Only notes under this path, relative to `root`.
+        #[arg(short, long, value_name = "PATH")]
+        prefix: Option<PathBuf>,
+        /// Order of the notes.
+        #[arg(short, long, value_enum, default_value_t)]
+        sort: ListOrder,
+        /// Number of notes to show.
+        #[arg(short = 'n', long, value_name = "NUM")]
+        limit: Option<usize>,
+    },
+    /// Show how many notes are due now, today and this week.
+    Status,
+    /// Move or rename a file (or directory) together with its metadata.
+    Mv {
+        /// File or directory to move.
+        #[arg(value_name = "SRC")]
+        source: PathBuf,
+        /// New path, or an existing directory to move into.
+        #[arg(value_name = "DST")]
+        destination: PathBuf,
+    },
+    /// Import notes and their schedules from other software.
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    /// Export schedules of all notes as a table.
+    Export {
+        /// File to write (default: standard output).
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Table format (default: judged from the file extension, or CSV).
+        #[arg(short, long)]
+        format: Option<TableFormat>,
+    },
+    /// Upgrade the database to the current format.
+    Migrate,
+    /// Check the database for broken, orphaned and duplicate metadata.
+    Doctor {
+        /// Remove orphans, set broken metadata aside, merge duplicates and rebuild the index.
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Reattach notes whose files were moved or renamed under `root`.
+    Relink {
+        /// Only show which notes would be relinked.
+        #[arg(long)]
+        dry_run: bool,
+        /// Relink notes matched only by inode (moved and then edited) without asking.
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Take a snapshot of the database.
+    Backup {
+        /// Number of snapshots to keep, removing older ones (0 keeps all; default: `backup_retention` in config).
+        #[arg(short, long, value_name = "N")]
+        keep: Option<usize>,
+        /// List snapshots instead of taking one.
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Replace the database with a snapshot taken by `astronote backup`.
+    Restore {
+        #[arg(value_name = "SNAPSHOT")]
+        snapshot: PathBuf,
+    },
+    /// Reconcile the database with another one, e.g. on a shared folder.
+    Sync {
+        /// Path to the other database directory.
+        #[arg(value_name = "OTHER_DB_PATH")]
+        other: PathBuf,
+    },
+    /// List the collections in the config file and how many of their notes are due.
+    Collections,
+    /// Merge conflicting versions of a metadata file; meant to be run by git.
+    MergeDriver {
+        /// Common ancestor (unused; review logs only grow).
+        #[arg(value_name = "BASE")]
+        base: PathBuf,
+        /// Our version, which is replaced with the merge result.
+        #[arg(value_name = "OURS")]
+        ours: PathBuf,
+        /// Their version.
+        #[arg(value_name = "THEIRS")]
+        theirs: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ImportSource {
+    /// Export each note of an Anki collection to a markdown file and keep its schedule.
+    Anki {
+        /// Anki collection (`collection.anki2`) or deck package (`.apkg`).
+        // not `collection`, which is the global `--collection` option
+        #[arg(value_name = "COLLECTION")]
+        file: PathBuf,
+        /// Directory under `root` to write the markdown files into.
+        #[arg(short, long, value_name = "DIR", default_value = "anki")]
+        output_dir: PathBuf,
+        /// Content of each file, e.g. "# {{Front}}\n\n{{Back}}" (default: every field under a heading).
+        #[arg(short, long)]
+        template: Option<String>,
+        /// Field to name the files after (default: the first field).
+        #[arg(short, long, value_name = "FIELD")]
+        name_field: Option<String>,
+    },
+    /// Create or update notes from a table written by `astronote export`.
+    Table {
+        /// CSV or TSV file.
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        /// Table format (default: judged from the file extension, or CSV).
+        #[arg(short, long)]
+        format: Option<TableFormat>,
+    },
 }
 
 pub fn read_line() -> String {
@@ -1,9 +1,12 @@
 use anyhow::{anyhow, Context, Result};
+use astronote_core::schedulers::sm2::SuperMemo2;
+use astronote_core::schedulers::SchedulingAlgorithm;
 use figment::{
     providers::{Format, Serialized, Toml},
     Figment,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
 const CONFIG_FILE_NAME: &str = ".astronote.toml";
@@ -13,6 +16,71 @@ pub struct Config {
     pub database_path: String,
     pub editor_command: String,
     pub root: String,
+    /// where scheduling state is stored
+    pub storage: Storage,
+    pub backup_path: String,
+    /// take a snapshot before commands which overwrite or remove metadata
+    pub auto_backup: bool,
+    /// number of snapshots to keep (0 keeps all)
+    pub backup_retention: usize,
+    /// glob patterns of files under `root` which `astronote scan` adds, e.g. `["**/*.md"]`
+    pub auto_add: Vec<String>,
+    /// named collections, selected with `--collection`
+    pub collections: BTreeMap<String, Collection>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Storage {
+    /// metadata files in `database_path`
+    Database,
+    /// front matter of the markdown notes themselves
+    FrontMatter,
+    /// an embedded key-value store in `database_path`
+    KeyValue,
+}
+
+impl Storage {
+    /// Name of the storage in the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Database => "database",
+            Self::FrontMatter => "front-matter",
+            Self::KeyValue => "key-value",
+        }
+    }
+}
+
+/// A set of notes with a review queue of its own, kept in the same database.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Collection {
+    /// root directory of the notes; a relative path is taken from `root` (default: `root`)
+    pub root: String,
+    /// scheduler of notes added to the collection
+    pub scheduler: DefaultScheduler,
+    /// maximum number of reviews a day
+    pub daily_review_limit: Option<usize>,
+    /// maximum number of notes reviewed for the first time a day
+    pub daily_new_limit: Option<usize>,
+    /// glob patterns of files under the collection's root which `astronote scan` adds
+    pub auto_add: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DefaultScheduler {
+    /// SuperMemo 2 with its initial parameters
+    #[default]
+    Sm2,
+}
+
+impl DefaultScheduler {
+    pub fn build(&self) -> Box<dyn SchedulingAlgorithm> {
+        match self {
+            Self::Sm2 => Box::<SuperMemo2>::default(),
+        }
+    }
 }
 
 impl Default for Config {
@@ -21,6 +89,12 @@ impl Default for Config {
             database_path: String::from("./.astronote.db"),
             editor_command: String::from("vim"),
             root: String::from("./"),
+            storage: Storage::Database,
+            backup_path: String::from("./.astronote.backups"),
+            auto_backup: false,
+            backup_retention: 10,
+            auto_add: vec![],
+            collections: BTreeMap::new(),
         }
     }
 }
@@ -32,8 +106,88 @@ impl Config {
             .extract()
             .with_context(|| "Failed to build config")?;
         config.root = find_config_root_absolute(&builder)?;
+        // snapshots only hold metadata files; refuse rather than silently take none
+        if config.auto_backup && config.storage != Storage::Database {
+            return Err(anyhow!(
+                "`auto_backup = true` only works with `storage = \"database\"`, not `storage = \"{}\"`",
+                config.storage.name()
+            ));
+        }
         Ok(config)
     }
+
+    /// Settings of the collection `name` with its root made absolute.
+    ///
+    /// `None` is the collection of the notes under `root` itself, which has no limits
+    /// and takes `auto_add` from the top level.
+    pub fn collection(&self, name: Option<&str>) -> Result<Collection> {
+        let Some(name) = name else {
+            return Ok(Collection {
+                root: self.root.clone(),
+                auto_add: self.auto_add.clone(),
+                ..Collection::default()
+            });
+        };
+        // the name is a directory in the database
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_name {
+            return Err(anyhow!(
+                "Invalid collection name `{name}`; use letters, digits, `-` and `_`"
+            ));
+        }
+        let collection = self.collections.get(name).ok_or(anyhow!(
+            "Unknown collection `{name}`; see `astronote collections`"
+        ))?;
+        let root = Path::new(&self.root).join(&collection.root);
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to make path absolute: {root:?}"))?;
+        Ok(Collection {
+            root: root.to_string_lossy().to_string(),
+            ..collection.clone()
+        })
+    }
+
+    /// Roots of the other collections under the root of the collection `name`, relative to it.
+    ///
+    /// With front matter, notes are told apart by where they are, so the notes under
+    /// these roots belong to those collections only; collections sharing a root are an error.
+    pub fn nested_roots(&self, name: Option<&str>) -> Result<Vec<PathBuf>> {
+        let root = Path::new(&self.collection(name)?.root).canonicalize()?;
+        let others = std::iter::once(None)
+            .chain(self.collections.keys().map(|other| Some(other.as_str())))
+            .filter(|other| *other != name);
+        let mut nested = vec![];
+        for other in others {
+            // a collection whose root is missing has no notes
+            let Some(other_root) = self
+                .collection(other)
+                .ok()
+                .and_then(|collection| Path::new(&collection.root).canonicalize().ok())
+            else {
+                continue;
+            };
+            if other_root == root {
+                let describe = |name: Option<&str>| {
+                    name.map_or("the default collection".to_string(), |name| {
+                        format!("collection `{name}`")
+                    })
+                };
+                return Err(anyhow!(
+                    "{} and {} share the root {root:?}; give each a root of its own",
+                    describe(name),
+                    describe(other)
+                ));
+            }
+            if let Ok(relative_path) = other_root.strip_prefix(&root) {
+                nested.push(relative_path.to_path_buf());
+            }
+        }
+        Ok(nested)
+    }
 }
 
 pub fn build_config() -> Result<Figment> {
@@ -81,7 +235,7 @@ fn find_config_root(builder: &Figment) -> Result<String> {
             let source = root_metadata.source.clone().unwrap().to_string();
             let config_root = source.strip_suffix(CONFIG_FILE_NAME).unwrap().to_string();
             // TODO: canonicalize doesn't expand some expression like `~`
-            let config_root = Path::new(&config_root).join(&path).canonicalize()?;
+            let config_root = Path::new(&config_root).join(path).canonicalize()?;
             config_root
         } else {
             path.to_path_buf()
@@ -112,7 +266,7 @@ fn find_config_root(builder: &Figment) -> Result<String> {
         .with_context(|| "Failed to get current directory")?
         .to_string_lossy()
         .to_string();
-    return Ok(current_dir);
+    Ok(current_dir)
 }
 
 #[cfg(test)]
@@ -166,4 +320,43 @@ mod test {
         let home_dir = dir.path().join("../../usr/").canonicalize().unwrap();
         assert_eq!(home_dir.to_string_lossy().to_string(), root)
     }
+
+    #[test]
+    fn resolve_collection() {
+        let dir = TempDir::new("test").unwrap();
+        std::fs::create_dir(dir.path().join("wiki")).unwrap();
+        let file_path = dir.path().join(CONFIG_FILE_NAME);
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(
+            b"auto_add = ['**/*.md']\n[collections.wiki]\nroot = 'wiki'\ndaily_review_limit = 20",
+        )
+        .unwrap();
+        file.flush().unwrap();
+
+        let builder = Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::file(&file_path));
+        let mut config: Config = builder.extract().unwrap();
+        config.root = find_config_root_absolute(&builder).unwrap();
+        let wiki = config.collection(Some("wiki")).unwrap();
+        let wiki_root = dir.path().join("wiki").canonicalize().unwrap();
+        assert_eq!(wiki.root, wiki_root.to_string_lossy());
+        assert_eq!(wiki.daily_review_limit, Some(20));
+        assert_eq!(wiki.daily_new_limit, None);
+        assert!(wiki.auto_add.is_empty());
+        let default = config.collection(None).unwrap();
+        assert_eq!(default.root, config.root);
+        assert_eq!(default.auto_add, ["**/*.md"]);
+        assert!(config.collection(Some("missing")).is_err());
+        assert!(config.collection(Some("../wiki")).is_err());
+
+        assert_eq!(config.nested_roots(None).unwrap(), [Path::new("wiki")]);
+        assert!(config.nested_roots(Some("wiki")).unwrap().is_empty());
+        let same = Collection {
+            root: ".".to_string(),
+            ..Default::default()
+        };
+        config.collections.insert("same".to_string(), same);
+        assert!(config.nested_roots(None).is_err());
+    }
 }
@@ -0,0 +1,141 @@
+//! Number of reviews done today, for the daily limits of collections.
+//!
+//! The counts are kept in a small file in the database directory of each collection,
+//! so that checking the limits does not need to load every note.
+
+use crate::config::Collection;
+use anyhow::{Context, Result};
+use astronote_core::db::{write_atomically, LOCK_FILE_NAME};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Directory in the database directory which holds state other than notes. The file
+/// is replaced there rather than next to the notes, whose directory must stay untouched
+/// for the database index to be trusted.
+pub const STATE_DIR_NAME: &str = ".astronote.state";
+
+/// File in [`STATE_DIR_NAME`] which holds the counts of the day.
+pub const DAILY_FILE_NAME: &str = "daily";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DailyCount {
+    pub date: NaiveDate,
+    /// reviews done on `date`
+    pub reviews: usize,
+    /// notes reviewed for the first time on `date`
+    pub new: usize,
+}
+
+impl DailyCount {
+    /// Counts of `today` recorded in `db_path`; nothing has been recorded yet if the
+    /// file is missing or was written on another day.
+    pub fn load(db_path: &Path, today: NaiveDate) -> Result<Self> {
+        let path = daily_path(db_path);
+        let count = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str::<Self>(&json)
+                .with_context(|| format!("Failed to parse {path:?}"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Self::empty(today),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+        };
+        if count.date == today {
+            Ok(count)
+        } else {
+            Ok(Self::empty(today))
+        }
+    }
+
+    /// Count a review done `today` in the file in `db_path`.
+    ///
+    /// The database is locked meanwhile, so that reviews counted at the same time
+    /// by another astronote are not lost.
+    pub fn record(db_path: &Path, today: NaiveDate, is_new: bool) -> Result<()> {
+        let state_dir = db_path.join(STATE_DIR_NAME);
+        fs::create_dir_all(&state_dir)
+            .with_context(|| format!("Failed to create directory {state_dir:?}"))?;
+        let _lock = lock(db_path)?;
+        let mut count = Self::load(db_path, today)?;
+        count.reviews += 1;
+        if is_new {
+            count.new += 1;
+        }
+        write_atomically(
+            &daily_path(db_path),
+            serde_json::to_string(&count)?.as_bytes(),
+        )
+    }
+
+    /// Numbers of reviews and of new notes the daily limits of `collection` still allow.
+    pub fn remaining(&self, collection: &Collection) -> (usize, usize) {
+        let left = |limit: Option<usize>, used: usize| {
+            limit.map_or(usize::MAX, |limit| limit.saturating_sub(used))
+        };
+        (
+            left(collection.daily_review_limit, self.reviews),
+            left(collection.daily_new_limit, self.new),
+        )
+    }
+
+    fn empty(date: NaiveDate) -> Self {
+        Self {
+            date,
+            reviews: 0,
+            new: 0,
+        }
+    }
+}
+
+fn daily_path(db_path: &Path) -> PathBuf {
+    db_path.join(STATE_DIR_NAME).join(DAILY_FILE_NAME)
+}
+
+// Take the exclusive lock of the database, like writes of notes do.
+fn lock(db_path: &Path) -> Result<File> {
+    let lock_path = db_path.join(LOCK_FILE_NAME);
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file: {lock_path:?}"))?;
+    file.lock()
+        .with_context(|| format!("Failed to lock database: {db_path:?}"))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn count_reviews_of_the_day() {
+        let dir = TempDir::new("daily").unwrap();
+        let db_path = dir.path().join("db");
+        let today = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let collection = Collection {
+            daily_review_limit: Some(3),
+            daily_new_limit: Some(1),
+            ..Default::default()
+        };
+        let count = DailyCount::load(&db_path, today).unwrap();
+        assert_eq!(count.remaining(&collection), (3, 1));
+
+        DailyCount::record(&db_path, today, true).unwrap();
+        DailyCount::record(&db_path, today, false).unwrap();
+        let count = DailyCount::load(&db_path, today).unwrap();
+        assert_eq!((count.reviews, count.new), (2, 1));
+        assert_eq!(count.remaining(&collection), (1, 0));
+        assert_eq!(
+            count.remaining(&Collection::default()),
+            (usize::MAX, usize::MAX)
+        );
+
+        // the counts start over the next day
+        let tomorrow = today.succ_opt().unwrap();
+        let count = DailyCount::load(&db_path, tomorrow).unwrap();
+        assert_eq!(count.remaining(&collection), (3, 1));
+    }
+}
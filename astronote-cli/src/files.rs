@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::path::{Path, PathBuf};
+
+/// File listing paths which `astronote add -r` should skip, in `.gitignore` syntax.
+pub const IGNORE_FILE_NAME: &str = ".astronoteignore";
+
+/// List files under `dir` recursively, as `astronote add -r` and `astronote relink` see them.
+///
+/// Hidden entries (such as `.git` or `.astronote.db`), directories in `excluded` and
+/// files ignored by `.gitignore` or [`IGNORE_FILE_NAME`] files are left out, whether
+/// or not `dir` is in a git repository.
+pub fn walk_files(dir: &Path, excluded: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let excluded = excluded.to_vec();
+    let mut files = vec![];
+    let walker = WalkBuilder::new(dir)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .filter_entry(move |entry| !excluded.iter().any(|path| path == entry.path()))
+        .build();
+    for entry in walker {
+        let entry = entry.with_context(|| format!("Failed to read entries of {dir:?}"))?;
+        if entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            files.push(entry.into_path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Include and exclude glob patterns for paths relative to `root`, see [`glob`].
+#[derive(Debug)]
+pub struct PathFilter {
+    /// no patterns includes every path
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(glob_set(include)?)
+        };
+        Ok(Self {
+            include,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    pub fn is_match(&self, relative_path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative_path))
+            && !self.exclude.is_match(relative_path)
+    }
+}
+
+/// Glob pattern as every command takes it: `*` stays within a directory and `**`
+/// crosses directories, so `*.md` only matches at the top and `**/*.md` matches everywhere.
+pub fn glob(pattern: &str) -> Result<Glob> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid pattern: {pattern:?}"))
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(glob(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn walk_and_filter_files() {
+        let dir = TempDir::new("walk").unwrap();
+        let root = dir.path();
+        for path in ["a.md", "b.txt", "drafts/c.md", "build/d.md", ".hidden/e.md"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "build/\n").unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.txt\n").unwrap();
+
+        let files = walk_files(root, &[root.join("drafts")]).unwrap();
+        assert_eq!(files, [root.join("a.md")]);
+        let files = walk_files(root, &[]).unwrap();
+        assert_eq!(files, [root.join("a.md"), root.join("drafts/c.md")]);
+
+        let filter = PathFilter::new(&["**/*.md".to_string()], &["drafts/*".to_string()]).unwrap();
+        assert!(filter.is_match(Path::new("notes/a.md")));
+        assert!(filter.is_match(Path::new("drafts/old/c.md")));
+        assert!(!filter.is_match(Path::new("drafts/c.md")));
+        assert!(!filter.is_match(Path::new("b.txt")));
+        assert!(PathFilter::new(&[], &[])
+            .unwrap()
+            .is_match(Path::new("b.txt")));
+        // `*` stays within a directory
+        let filter = PathFilter::new(&["*.md".to_string()], &[]).unwrap();
+        assert!(!filter.is_match(Path::new("notes/a.md")));
+    }
+}
@@ -1,2 +1,10 @@
+pub mod anki;
 pub mod cli;
 pub mod config;
+pub mod daily;
+pub mod files;
+pub mod overview;
+pub mod relink;
+pub mod remove;
+pub mod review;
+pub mod table;
@@ -1,8 +1,20 @@
-use astronote_cli::cli::{CommandParser, Commands};
-use astronote_cli::config::Config;
+use astronote_cli::anki;
+use astronote_cli::cli::{CommandParser, Commands, ImportSource};
+use astronote_cli::config::{Collection, Config, Storage};
+use astronote_cli::daily::DailyCount;
+use astronote_cli::overview::{list_items, relative_due, Status};
 use astronote_cli::prompt;
+use astronote_cli::files::{walk_files, PathFilter};
+use astronote_cli::relink::plan_relink;
+use astronote_cli::remove::RemoveTargets;
+use astronote_cli::review::{grade, next_note, ReviewItem};
+use astronote_cli::table::{read_table, split_unwritable, write_table, TableFormat};
 use astronote_core::Note;
 use astronote_core::db::ron::*;
+use astronote_core::db::front_matter::FrontMatterRepository;
+use astronote_core::db::index::IndexEntry;
+use astronote_core::db::kv::KeyValueRepository;
+use astronote_core::db::{collection_path, ConflictError, NotFoundError, Repository};
 use colored::Colorize;
 use anyhow::{anyhow, Context, Result};
 
@@ -10,68 +22,254 @@ use anyhow::{anyhow, Context, Result};
 async fn main() -> Result<()> {
     // load config file
     let config = Config::try_new().with_context(|| "Failed to build config")?;
-    
-    let config_root = Path::new(&config.root).canonicalize()?;
 
     // parse command line arguments
     let parser = CommandParser::parse_args();
 
+    // git runs the merge driver anywhere in the work tree; don't touch the database
+    if let Commands::MergeDriver { ours, theirs, .. } = &parser.subcommand {
+        return merge_driver(ours, theirs);
+    }
+
+    let collection = config.collection(parser.collection.as_deref())?;
+    let config_root = Path::new(&collection.root).canonicalize()?;
+
     // use argument url if it is provided, otherwise use config file
-    let db_path = parser.database_path().unwrap_or(config.database_path);
-    // create DB connection
+    let db_path = parser.database_path().unwrap_or(config.database_path.clone());
     let db_path = PathBuf::from(&db_path);
-    let repo = NoteRepository::new(&db_path)?;
+    if let Commands::Collections = &parser.subcommand {
+        return list_collections(&config, &db_path);
+    }
+    // each collection has a database and snapshots of its own
+    let (db_path, backup_dir) = match &parser.collection {
+        Some(name) => (
+            collection_path(&db_path, name),
+            PathBuf::from(&config.backup_path).join(name),
+        ),
+        None => (db_path, PathBuf::from(&config.backup_path)),
+    };
+    // commands which work on the metadata files themselves; other storages refuse them up front
+    let database = |command: &str| -> Result<NoteRepository> {
+        if config.storage != Storage::Database {
+            return Err(anyhow!(
+                "`astronote {}` is not supported for `storage = \"{}\"`; it only works with `storage = \"database\"`",
+                command,
+                config.storage.name()
+            ));
+        }
+        NoteRepository::new(&db_path)
+    };
+    let nested_roots = match config.storage {
+        Storage::FrontMatter => config.nested_roots(parser.collection.as_deref())?,
+        _ => vec![],
+    };
+    // never taken for notes: the database, and with front matter, the notes of other collections
+    let excluded = db_path
+        .canonicalize()
+        .into_iter()
+        .chain(nested_roots.iter().map(|dir| config_root.join(dir)))
+        .collect::<Vec<_>>();
+    let repo = open_repository(config.storage, &db_path, &config_root, nested_roots)?;
+    // snapshot before commands which overwrite or remove metadata, if enabled;
+    // the config is rejected if it is enabled for another storage
+    let auto_backup = || -> Result<()> {
+        if config.auto_backup {
+            take_snapshot(&database("backup")?, &backup_dir, config.backup_retention)?;
+        }
+        Ok(())
+    };
 
     // main logic; subcommands
     match parser.subcommand {
         // Add file metadata to DB
-        Commands::Add { files } => {
-            // validate file paths
-            let validated_pathes = files
-                .iter()
-                .map(|path| get_validated_path(path, &config_root) )
-                .collect::<Result<Vec<_>>>()?;
+        Commands::Add { files, recursive, include, exclude } => {
+            let filter = PathFilter::new(&include, &exclude)?;
+            // validate file paths, and expand directories
+            let mut candidates = vec![];
+            let mut skipped = 0;
+            for path in files.iter() {
+                let validated_path = get_validated_path(path, &config_root)?;
+                let absolute_path = config_root.join(&validated_path);
+                if absolute_path.is_file() {
+                    candidates.push(validated_path);
+                } else if absolute_path.is_dir() && recursive {
+                    for file in walk_files(&absolute_path, &excluded)? {
+                        candidates.push(file.strip_prefix(&config_root)?.to_path_buf());
+                    }
+                } else {
+                    println!("{} {} (use `-r` to add files under a directory)", "Skipped".yellow(), path.display());
+                    skipped += 1;
+                }
+            }
+            let tracked = tracked_pathes(repo.as_ref())?;
+            let mut already_tracked = 0;
+            let mut pathes = BTreeSet::new();
+            for path in candidates {
+                if !filter.is_match(&path) {
+                    skipped += 1;
+                } else if tracked.contains(&path) {
+                    already_tracked += 1;
+                } else {
+                    pathes.insert(path);
+                }
+            }
             // note from validated file
-            let notes = validated_pathes
-                .into_iter()
-                .filter(|path| path.is_file())
-                .map(|path| Note::new_default(path.to_str().unwrap()) )
-                .collect::<Vec<Note>>();
+            let notes = new_notes(pathes, &config_root, &collection)?;
             let len = notes.len();
             repo.create(notes)?;
             // print result
             println!(
-                "{} {} {}",
+                "{} {} {}, {} already tracked, {} skipped",
                 "Added".green(),
                 len,
-                "notes".green()
+                "notes".green(),
+                already_tracked,
+                skipped,
             );
         }
+        // Remove file metadata from DB
+        Commands::Remove { files, missing, dry_run, yes } => {
+            if files.is_empty() && !missing {
+                return Err(anyhow!("Specify files to remove, or `--missing`"));
+            }
+            let current_dir = std::env::current_dir()?;
+            let targets = RemoveTargets::new(&files, &config_root, &current_dir, missing)?;
+            let report = repo.get_all_lenient().with_context(|| "Failed to retreive note metadata")?;
+            print_load_errors(&report.errors);
+            let notes = targets.select(report.notes, &config_root);
+            let action = if dry_run { "Would remove" } else { "Remove" };
+            for note in notes.iter() {
+                println!("{} {}", action.yellow(), note.relative_path.display());
+            }
+            if dry_run {
+                println!("{} {} {}", action.green(), notes.len(), "notes".green());
+                return Ok(());
+            }
+            if !yes && !notes.is_empty() {
+                let answer = prompt!("Remove {} notes? [y/N]: ", notes.len());
+                if !matches!(answer.as_str(), "y" | "Y" | "yes") {
+                    println!("{}", "Cancelled".yellow());
+                    return Ok(());
+                }
+            }
+            auto_backup()?;
+            let len = notes.len();
+            repo.delete(notes)?;
+            println!("{} {} {}", "Removed".green(), len, "notes".green());
+        }
+        // Add untracked files matching `auto_add`
+        Commands::Scan { interactive } => {
+            if collection.auto_add.is_empty() {
+                return Err(anyhow!("Nothing to scan for; set `auto_add` in config, e.g. `auto_add = [\"**/*.md\"]`"));
+            }
+            let filter = PathFilter::new(&collection.auto_add, &[])?;
+            let tracked = tracked_pathes(repo.as_ref())?;
+            let mut pathes = vec![];
+            for file in walk_files(&config_root, &excluded)? {
+                let path = file.strip_prefix(&config_root)?.to_path_buf();
+                if !filter.is_match(&path) || tracked.contains(&path) {
+                    continue;
+                }
+                if interactive {
+                    let answer = prompt!("Add {}? [y/N]: ", path.display());
+                    if !matches!(answer.as_str(), "y" | "Y" | "yes") {
+                        continue;
+                    }
+                } else {
+                    println!("{} {}", "New".green(), path.display());
+                }
+                pathes.push(path);
+            }
+            // notes whose files were deleted, moved or renamed
+            let mut missing = tracked
+                .into_iter()
+                .filter(|path| !config_root.join(path).exists())
+                .collect::<Vec<_>>();
+            missing.sort();
+            for path in missing.iter() {
+                println!("{} {}", "Missing:".yellow(), path.display());
+            }
+            if !missing.is_empty() {
+                println!("Run `astronote relink` if they were moved, or `astronote remove --missing` to stop reviewing them");
+            }
+            let notes = new_notes(pathes, &config_root, &collection)?;
+            let len = notes.len();
+            repo.create(notes)?;
+            println!("{} {} {}", "Added".green(), len, "notes".green());
+        }
         // main; review file in DB
         Commands::Review { num, ignore_schedule } => {
-            // get `num` of old notes
-            let notes: Vec<Note> = {
-                let mut notes = repo.get_all().with_context(|| "Failed to retreive note metadata")?;
-                notes.sort_by_key(|note| note.next_datetime); // sort by date
-                let notes_to_review = notes.into_iter()
-                    .filter(|note| {
-                        let is_overdue = note.next_datetime <= chrono::Local::now().naive_local();
-                        let ignore_schedule = ignore_schedule.unwrap_or(false);
-                        is_overdue | ignore_schedule
-                    })
-                    .take(num.unwrap_or(std::usize::MAX))
-                    .collect::<Vec<_>>();
-                anyhow::Ok(notes_to_review)
-            }?;
-            if notes.len() < 1 {
+            // get `num` of old notes from the due-date index
+            let before = if ignore_schedule.unwrap_or(false) {
+                chrono::NaiveDateTime::MAX
+            } else {
+                chrono::Local::now().naive_local()
+            };
+            let (mut reviews_left, mut new_left) = remaining_today(&db_path, &collection)?;
+            if reviews_left == 0 {
+                println!("The daily review limit of this collection has been reached!");
+                return Ok(());
+            }
+            reviews_left = reviews_left.min(num.unwrap_or(usize::MAX));
+            // broken notes are left out of the queue; warn about them before reviewing
+            let broken = repo.get_broken().with_context(|| "Failed to retreive broken notes")?;
+            for path in broken.iter() {
+                println!("{} {:?} failed to load", "Warning:".yellow(), path);
+            }
+            print_skipped(broken.len());
+            // skipped notes (broken, unsupported or new over the limit) don't count
+            // towards `num`, so take the whole queue and stop after enough reviews
+            let entries = repo
+                .get_due(&before, usize::MAX)
+                .with_context(|| "Failed to retreive due notes")?;
+            if entries.is_empty() {
                 println!("There is no file to review (for now)!");
                 return Ok(());
             }
+            // load each note in the background while the previous one is being reviewed
+            let mut entries = entries.into_iter();
+            let prefetch = |entry: Option<IndexEntry>| {
+                let repo = Arc::clone(&repo);
+                tokio::task::spawn_blocking(move || {
+                    entry.map(|entry| {
+                        let path = entry.relative_path;
+                        let note = repo.get_one(&path);
+                        (path, note)
+                    })
+                })
+            };
+            let mut next = prefetch(entries.next());
             // for each file, open it with editor and update the metadata accordingly
-            for mut note in notes {
-                let validated_path = get_validated_path(&Path::new(&note.relative_path), &config_root)?;
-                let absolute_path = Path::new(&validated_path).canonicalize()?;
-                println!("{} {}", "Reviewing".green(), absolute_path.to_str().unwrap());
+            while let Some((path, note)) = next.await? {
+                if reviews_left == 0 {
+                    break;
+                }
+                next = prefetch(entries.next());
+                // keep reviewing healthy notes even if some metadata is broken
+                let mut note = match note {
+                    Ok(note) => note,
+                    Err(err) => {
+                        print_load_errors(&[(path, err)]);
+                        continue;
+                    }
+                };
+                if let Some(unsupported) = note.scheduler.unsupported() {
+                    println!(
+                        "{} {}: unsupported scheduler `{}`",
+                        "Skipping".yellow(),
+                        note.relative_path.display(),
+                        unsupported.type_name()
+                    );
+                    continue;
+                }
+                let is_new = note.reviews.is_empty();
+                if is_new && new_left == 0 {
+                    continue;
+                }
+                // the working directory may be outside the root of the collection
+                let validated_path = get_validated_path(&config_root.join(&note.relative_path), &config_root)?;
+                let absolute_path = config_root.join(&validated_path);
+                println!("{} {}", "Reviewing".green(), absolute_path.display());
 
                 // let users choose which editor to use
                 let program = match prompt!(
@@ -85,8 +283,7 @@ async fn main() -> Result<()> {
                 };
                 // open the note with editor
                 Command::new(&program)
-                    // .arg(&absolute_path)
-                    .arg(&validated_path)
+                    .arg(&absolute_path)
                     .status()?
                     .success()
                     .then_some(())
@@ -94,9 +291,11 @@ async fn main() -> Result<()> {
 
                 // update the metadata
                 let quality = input_quality(&note);
-                note.next_datetime = note
-                    .scheduler
-                    .update_and_calculate_next_datetime(quality as u8);
+                grade(&mut note, &absolute_path, quality as u8)?;
+                reviews_left -= 1;
+                if is_new {
+                    new_left -= 1;
+                }
 
                 // print result
                 println!(
@@ -107,17 +306,552 @@ async fn main() -> Result<()> {
                 println!();
 
                 // store the updated metadata into DB
-                repo.update(vec![note]).with_context(|| "Failed to update note metadata")?;
+                // if another session has graded the note meanwhile, keep its result
+                match repo.update(vec![note]) {
+                    Ok(()) => record_review(&db_path, &collection, is_new)?,
+                    Err(err) => match err.downcast_ref::<ConflictError>() {
+                        Some(conflict) => println!("{} {}", "Conflict:".red(), conflict),
+                        None => return Err(err.context("Failed to update note metadata")),
+                    },
+                }
             }
         }
+        // The next note to review, without opening an editor
+        Commands::Next { json, ignore_schedule } => {
+            let before = if ignore_schedule {
+                chrono::NaiveDateTime::MAX
+            } else {
+                chrono::Local::now().naive_local()
+            };
+            let (reviews_left, new_left) = remaining_today(&db_path, &collection)?;
+            let note = match reviews_left {
+                0 => None,
+                _ => next_note(repo.as_ref(), &before, new_left)?,
+            };
+            let item = note.map(|note| ReviewItem::new(&note, &config_root));
+            match item {
+                _ if json => println!("{}", serde_json::to_string(&item)?),
+                Some(item) => println!("{}", item.absolute_path.display()),
+                None => println!("There is no file to review (for now)!"),
+            }
+        }
+        // Grade a note without prompting
+        Commands::Grade { file, grade: quality, json } => {
+            let validated_path = get_validated_path(&file, &config_root)?;
+            let absolute_path = config_root.join(&validated_path);
+            let mut note = repo.get_one(&validated_path)?;
+            let is_new = note.reviews.is_empty();
+            grade(&mut note, &absolute_path, quality)?;
+            let item = ReviewItem::new(&note, &config_root);
+            repo.update(vec![note]).with_context(|| "Failed to update note metadata")?;
+            record_review(&db_path, &collection, is_new)?;
+            if json {
+                println!("{}", serde_json::to_string(&item)?);
+            } else {
+                println!("{} {}", "Next datetime:".green(), item.next_datetime);
+            }
+        }
+        Commands::List { prefix, sort, limit } => {
+            let report = repo
+                .get_all_lenient()
+                .with_context(|| "Failed to retreive note metadata")?;
+            print_load_errors(&report.errors);
+            let items = list_items(report.notes, prefix.as_deref(), sort, limit.unwrap_or(usize::MAX))?;
+            if items.is_empty() {
+                println!("There is no note to list");
+                return Ok(());
+            }
+            let now = chrono::Local::now().naive_local();
+            let width = items
+                .iter()
+                .map(|item| item.path.display().to_string().chars().count())
+                .max()
+                .unwrap_or_default();
+            for item in items {
+                let relative = relative_due(&item.next_datetime, &now);
+                let relative = if item.next_datetime <= now {
+                    relative.yellow()
+                } else {
+                    relative.normal()
+                };
+                println!(
+                    "{:<width$}  {}  {:<12}  {} {}",
+                    item.path.display().to_string(),
+                    item.next_datetime.format("%Y-%m-%d %H:%M"),
+                    relative,
+                    item.scheduler,
+                    item.parameters,
+                );
+            }
+        }
+        Commands::Status => {
+            let now = chrono::Local::now().naive_local();
+            let status = Status::of(repo.as_ref(), &now)?;
+            println!("{} {}", "Due now:".green(), status.due_now);
+            println!("{} {}", "Due today:".green(), status.due_today);
+            println!("{} {}", "Due this week:".green(), status.due_this_week);
+            println!("{} {}", "Total:".green(), status.total);
+        }
+        // Move file on disk and its metadata in DB together
+        Commands::Mv { source, destination } => {
+            let source_path = get_validated_path(&source, &config_root)?;
+            // like `mv`, moving into an existing directory keeps the file name
+            let destination = if destination.is_dir() {
+                let file_name = source
+                    .canonicalize()?
+                    .file_name()
+                    .ok_or(anyhow!("Cannot move {:?}", source))?
+                    .to_owned();
+                destination.join(file_name)
+            } else {
+                destination
+            };
+            let destination_path = get_validated_destination(&destination, &config_root)?;
+            // collect tracked notes at or under the source path;
+            // only their paths are needed, so broken notes elsewhere don't get in the way
+            let renames = if config_root.join(&source_path).is_dir() {
+                repo.get_due(&chrono::NaiveDateTime::MAX, usize::MAX)
+                    .with_context(|| "Failed to retreive note metadata")?
+                    .into_iter()
+                    .filter_map(|entry| {
+                        let path = entry.relative_path;
+                        let suffix = path.strip_prefix(&source_path).ok()?;
+                        Some((path.clone(), destination_path.join(suffix)))
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                repo.get_one(&source_path)
+                    .with_context(|| format!("{:?} is not tracked by astronote", source))?;
+                vec![(source_path.clone(), destination_path.clone())]
+            };
+            // move the file first, and move it back if the metadata cannot follow
+            let len = renames.len();
+            std::fs::rename(&source, &destination)
+                .with_context(|| format!("Failed to move {:?} to {:?}", source, destination))?;
+            if let Err(err) = repo.rename(renames) {
+                std::fs::rename(&destination, &source).with_context(|| {
+                    format!("Failed to move {:?} back to {:?}", destination, source)
+                })?;
+                return Err(err.context("Failed to move note metadata"));
+            }
+            println!(
+                "{} {:?} -> {:?} ({} {})",
+                "Moved".green(),
+                source_path,
+                destination_path,
+                len,
+                "notes".green()
+            );
+        }
+        // Import notes from other software
+        Commands::Import { source } => match source {
+            ImportSource::Anki { file, output_dir, template, name_field } => {
+                // validate everything before creating any file or directory
+                let collection = anki::read_collection(&file)?;
+                collection.check_fields(template.as_deref(), name_field.as_deref())?;
+                let output_dir = get_validated_directory(&output_dir, &config_root)
+                    .with_context(|| format!("{:?} is not under astronote `root`: {:?}", output_dir, config_root))?;
+                let absolute_output_dir = config_root.join(&output_dir);
+                std::fs::create_dir_all(&absolute_output_dir)
+                    .with_context(|| format!("Failed to create {:?}", absolute_output_dir))?;
+                let (notes, skipped) = anki::export_notes(
+                    &collection,
+                    &config_root,
+                    &output_dir,
+                    template.as_deref(),
+                    name_field.as_deref(),
+                )?;
+                let len = notes.len();
+                repo.create(notes)?;
+                println!(
+                    "{} {} {} ({} skipped; file already exists)",
+                    "Imported".green(),
+                    len,
+                    "notes".green(),
+                    skipped
+                );
+            }
+            ImportSource::Table { file, format } => {
+                let format = format.unwrap_or(TableFormat::from_path(&file));
+                let reader = std::fs::File::open(&file)
+                    .with_context(|| format!("Failed to open {:?}", file))?;
+                let rows = read_table(reader, format)?;
+                auto_backup()?;
+                let mut created = vec![];
+                let mut updated = vec![];
+                let mut errors = vec![];
+                let mut seen = HashSet::new();
+                for row in rows.iter() {
+                    let relative_path = match get_validated_row_path(&row.path, &config_root) {
+                        Ok(relative_path) => relative_path,
+                        Err(err) => {
+                            println!("{} {}: {:#}", "Skipping".yellow(), row.path, err);
+                            continue;
+                        }
+                    };
+                    // a note updated twice would conflict with itself
+                    if !seen.insert(relative_path.clone()) {
+                        println!("{} {}: listed more than once; the first row is used", "Skipping".yellow(), row.path);
+                        continue;
+                    }
+                    let result = match repo.get_one(&relative_path) {
+                        Ok(note) => row.apply(Some(note)).map(|note| updated.push(note)),
+                        Err(err) if err.downcast_ref::<NotFoundError>().is_some() => {
+                            // at the canonical path, as `add` would track it
+                            row.apply(Some(Note::new_default(&relative_path))).and_then(|mut note| {
+                                note.refresh_fingerprint(&config_root.join(&relative_path))?;
+                                created.push(note);
+                                Ok(())
+                            })
+                        }
+                        Err(err) => {
+                            errors.push((relative_path, err));
+                            continue;
+                        }
+                    };
+                    // e.g. a malformed date or a scheduler this astronote doesn't know
+                    if let Err(err) = result {
+                        println!("{} {}: {:#}", "Skipping".yellow(), row.path, err);
+                    }
+                }
+                let (created_len, updated_len) = (created.len(), updated.len());
+                repo.create(created)?;
+                repo.update(updated)?;
+                print_load_errors(&errors);
+                println!(
+                    "{} {} {} {} {}",
+                    "Created".green(),
+                    created_len,
+                    "and updated".green(),
+                    updated_len,
+                    "notes".green()
+                );
+            }
+        },
+        // Write schedules of all notes as CSV/TSV
+        Commands::Export { output, format } => {
+            let report = repo.get_all_lenient().with_context(|| "Failed to retreive note metadata")?;
+            // check every path before anything is written
+            let (mut notes, unwritable) = split_unwritable(report.notes);
+            notes.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+            match output {
+                Some(output) => {
+                    let format = format.unwrap_or(TableFormat::from_path(&output));
+                    let writer = std::fs::File::create(&output)
+                        .with_context(|| format!("Failed to create {:?}", output))?;
+                    write_table(notes, writer, format)?;
+                    print_load_errors(&report.errors);
+                }
+                None => {
+                    write_table(notes, std::io::stdout(), format.unwrap_or(TableFormat::Csv))?;
+                    // keep the table on stdout intact
+                    for (path, err) in report.errors.iter() {
+                        eprintln!("{} {:?}: {:#}", "Warning:".yellow(), path, err);
+                    }
+                }
+            }
+            // on standard error, which also keeps the table intact
+            for path in unwritable.iter() {
+                eprintln!("{} {:?}: path is not valid UTF-8 and cannot be written to a table", "Skipped".yellow(), path);
+            }
+        }
+        // Upgrade database written by older versions of astronote
+        Commands::Migrate => {
+            let repo = database("migrate")?;
+            auto_backup()?;
+            let migration = repo.migrate_layout()?;
+            for collision in migration.collisions.iter() {
+                println!(
+                    "{} metadata of {} was left in {:?}; {:?} already exists",
+                    "Collision:".red(),
+                    collision.relative_path.display(),
+                    collision.metadata_path,
+                    collision.existing_path,
+                );
+            }
+            print_load_errors(&migration.errors);
+            println!(
+                "{} {} {}",
+                "Moved".green(),
+                migration.moved.len(),
+                "metadata files".green()
+            );
+            let migration = repo.migrate_schema()?;
+            for metadata_path in migration.misplaced.iter() {
+                println!("{} {:?} was not upgraded; it is not where its note belongs", "Misplaced:".red(), metadata_path);
+            }
+            print_load_errors(&migration.errors);
+            println!(
+                "{} {} {}",
+                "Upgraded".green(),
+                migration.upgraded.len(),
+                "metadata files".green()
+            );
+        }
+        // Report (and fix) inconsistency between DB and files
+        Commands::Doctor { fix } => {
+            let repo = database("doctor")?;
+            let problems = repo.diagnose(&config_root)?;
+            for problem in problems.iter() {
+                match problem {
+                    Problem::Orphan { relative_path, .. } => {
+                        println!("{} {} does not exist", "Orphan:".yellow(), relative_path.display())
+                    }
+                    Problem::Corrupt { metadata_path, error } => {
+                        println!("{} {:?}: {}", "Corrupt:".red(), metadata_path, error)
+                    }
+                    Problem::NewerSchema { metadata_path, version } => println!(
+                        "{} {:?} was written by a newer astronote (schema version {})",
+                        "Newer:".yellow(),
+                        metadata_path,
+                        version
+                    ),
+                    Problem::Oversized { metadata_path, size } => {
+                        println!("{} {:?} ({} bytes)", "Oversized:".red(), metadata_path, size)
+                    }
+                    Problem::UnsupportedScheduler { relative_path, scheduler, .. } => println!(
+                        "{} {} uses unsupported scheduler `{}`",
+                        "Unsupported:".yellow(),
+                        relative_path.display(),
+                        scheduler
+                    ),
+                    Problem::Duplicate { canonical_path, metadata_pathes } => println!(
+                        "{} {:?} is tracked by {:?}",
+                        "Duplicate:".yellow(),
+                        canonical_path,
+                        metadata_pathes
+                    ),
+                }
+            }
+            // notes for newer versions of astronote are reported, but left as they are
+            let fixable = problems
+                .iter()
+                .filter(|problem| {
+                    !matches!(problem, Problem::UnsupportedScheduler { .. } | Problem::NewerSchema { .. })
+                })
+                .count();
+            if fixable == 0 {
+                // the index only notices files which are added, removed or replaced
+                if fix {
+                    repo.rebuild_index()?;
+                }
+                println!("{}", "No problem found".green());
+            } else if fix {
+                auto_backup()?;
+                repo.repair(&config_root, &problems)?;
+                println!("{} {} {}", "Fixed".green(), fixable, "problems".green());
+            } else {
+                println!("Run with `--fix` to fix {} problems", fixable);
+            }
+        }
+        // Find moved files of notes and update the notes accordingly
+        Commands::Relink { dry_run, yes } => {
+            let report = repo.get_all_lenient().with_context(|| "Failed to retreive note metadata")?;
+            print_load_errors(&report.errors);
+            let notes = report.notes;
+            let plan = plan_relink(&notes, &config_root, &excluded)?;
+            for (from, to) in plan.relinks.iter() {
+                println!("{} {:?} -> {:?}", "Relink".green(), from, to);
+            }
+            for (from, to) in plan.guessed.iter() {
+                println!("{} {:?} -> {:?} (same inode, content changed)", "Relink?".yellow(), from, to);
+            }
+            for (path, candidates) in plan.ambiguous.iter() {
+                println!("{} {:?} matches {:?}", "Ambiguous:".yellow(), path, candidates);
+            }
+            for path in plan.unmatched.iter() {
+                println!("{} {:?}", "No match:".yellow(), path);
+            }
+            if !dry_run {
+                let mut relinks = plan.relinks;
+                // inodes are reused, so a file with the same inode may be another file
+                if !plan.guessed.is_empty() {
+                    let answer = if yes {
+                        "y".to_string()
+                    } else {
+                        prompt!("Relink {} notes matched only by inode? [y/N]: ", plan.guessed.len())
+                    };
+                    if matches!(answer.as_str(), "y" | "Y" | "yes") {
+                        relinks.extend(plan.guessed);
+                    }
+                }
+                auto_backup()?;
+                let len = relinks.len();
+                repo.rename(relinks)?;
+                println!("{} {} {}", "Relinked".green(), len, "notes".green());
+            }
+        }
+        // Exchange reviews and deletions with another database
+        Commands::Sync { other } => {
+            let repo = database("sync")?;
+            let other = NoteRepository::open(&other)?;
+            auto_backup()?;
+            let report = repo.sync(&other)?;
+            for path in report.pulled.iter() {
+                println!("{} {}", "Pulled".green(), path.display());
+            }
+            for path in report.pushed.iter() {
+                println!("{} {}", "Pushed".green(), path.display());
+            }
+            for path in report.merged.iter() {
+                println!("{} {}", "Merged".green(), path.display());
+            }
+            for path in report.deleted.iter() {
+                println!("{} {}", "Deleted".green(), path.display());
+            }
+            let changes = [&report.pulled, &report.pushed, &report.merged, &report.deleted];
+            if changes.iter().all(|pathes| pathes.is_empty()) {
+                println!("{}", "Already in sync".green());
+            }
+            print_load_errors(&report.errors);
+        }
+        // Take a snapshot of the database
+        Commands::Backup { keep, list } => {
+            if list {
+                for snapshot in list_snapshots(&backup_dir)? {
+                    match read_manifest(&snapshot) {
+                        Ok(manifest) => println!(
+                            "{:?} ({} notes, schema version {})",
+                            snapshot, manifest.note_count, manifest.schema_version
+                        ),
+                        Err(err) => println!("{} {:?}: {:#}", "Warning:".yellow(), snapshot, err),
+                    }
+                }
+            } else {
+                take_snapshot(&database("backup")?, &backup_dir, keep.unwrap_or(config.backup_retention))?;
+            }
+        }
+        // Replace the database with a snapshot
+        Commands::Restore { snapshot } => {
+            let repo = database("restore")?;
+            auto_backup()?;
+            let manifest = repo.restore(&snapshot)?;
+            println!(
+                "{} {} {} from {:?}",
+                "Restored".green(),
+                manifest.note_count,
+                "notes".green(),
+                snapshot
+            );
+        }
+        Commands::MergeDriver { .. } => unreachable!("merge driver runs without a database"),
+        Commands::Collections => unreachable!("collections are listed before opening a database"),
     }
     Ok(())
 }
 
+use std::collections::{BTreeSet, HashSet};
 use std::fs::canonicalize;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
 use std::{path::Path, process::Command};
 
+// `nested_roots` are the roots of other collections under `root`, see `Config::nested_roots`
+fn open_repository(
+    storage: Storage,
+    db_path: &Path,
+    root: &Path,
+    nested_roots: Vec<PathBuf>,
+) -> Result<Arc<dyn Repository>> {
+    let repo: Arc<dyn Repository> = match storage {
+        Storage::Database => Arc::new(NoteRepository::new(db_path)?),
+        Storage::FrontMatter => Arc::new(FrontMatterRepository::new(root).excluding(nested_roots)),
+        Storage::KeyValue => Arc::new(KeyValueRepository::new(db_path)?),
+    };
+    Ok(repo)
+}
+
+// Relative paths of every note, from the due-date index.
+fn tracked_pathes(repo: &dyn Repository) -> Result<HashSet<PathBuf>> {
+    let entries = repo.get_due(&chrono::NaiveDateTime::MAX, usize::MAX)?;
+    Ok(entries.into_iter().map(|entry| entry.relative_path).collect())
+}
+
+fn new_notes(
+    pathes: impl IntoIterator<Item = PathBuf>,
+    root: &Path,
+    collection: &Collection,
+) -> Result<Vec<Note>> {
+    pathes
+        .into_iter()
+        .map(|path| {
+            let now = chrono::Local::now().naive_local();
+            let mut note = Note::new(&path, &now, collection.scheduler.build());
+            note.refresh_fingerprint(&root.join(&path))?;
+            Ok(note)
+        })
+        .collect()
+}
+
+fn list_collections(config: &Config, db_path: &Path) -> Result<()> {
+    if config.collections.is_empty() {
+        println!("There is no collection; add one as `[collections.<name>]` to the config file");
+        return Ok(());
+    }
+    let now = chrono::Local::now().naive_local();
+    for name in config.collections.keys() {
+        let collection = config.collection(Some(name))?;
+        let root = Path::new(&collection.root);
+        let nested_roots = match config.storage {
+            Storage::FrontMatter => config.nested_roots(Some(name))?,
+            _ => vec![],
+        };
+        let repo = open_repository(config.storage, &collection_path(db_path, name), root, nested_roots)?;
+        let due = repo.get_due(&now, usize::MAX)?.len();
+        println!("{} {} ({} due)", name.green(), collection.root, due);
+        let (reviews_left, new_left) = remaining_today(&collection_path(db_path, name), &collection)?;
+        if let Some(limit) = collection.daily_review_limit {
+            println!("    {reviews_left} of {limit} reviews left today");
+        }
+        if let Some(limit) = collection.daily_new_limit {
+            println!("    {new_left} of {limit} new notes left today");
+        }
+    }
+    Ok(())
+}
+
+// numbers of reviews and of new notes the daily limits still allow today
+fn remaining_today(db_path: &Path, collection: &Collection) -> Result<(usize, usize)> {
+    if !has_daily_limit(collection) {
+        return Ok((usize::MAX, usize::MAX));
+    }
+    let today = chrono::Local::now().date_naive();
+    Ok(DailyCount::load(db_path, today)?.remaining(collection))
+}
+
+// count a review towards the daily limits; nothing is written for collections without them
+fn record_review(db_path: &Path, collection: &Collection, is_new: bool) -> Result<()> {
+    if !has_daily_limit(collection) {
+        return Ok(());
+    }
+    DailyCount::record(db_path, chrono::Local::now().date_naive(), is_new)
+}
+
+fn has_daily_limit(collection: &Collection) -> bool {
+    collection.daily_review_limit.is_some() || collection.daily_new_limit.is_some()
+}
+
+// `keep` of 0 keeps every snapshot
+fn take_snapshot(repo: &NoteRepository, backup_dir: &Path, keep: usize) -> Result<()> {
+    let snapshot = repo.backup(backup_dir)?;
+    println!("{} {:?}", "Snapshot".green(), snapshot);
+    if keep > 0 {
+        for removed in prune_snapshots(backup_dir, keep)? {
+            println!("{} {:?}", "Removed old snapshot".green(), removed);
+        }
+    }
+    Ok(())
+}
+
+fn merge_driver(ours: &Path, theirs: &Path) -> Result<()> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))
+    };
+    let merged = merge_metadata(&read(ours)?, &read(theirs)?)?;
+    // git takes the content of `ours` as the result
+    std::fs::write(ours, merged).with_context(|| format!("Failed to write {:?}", ours))
+}
+
 fn get_validated_path(
     path: &Path,
     root: &Path,
@@ -126,14 +860,87 @@ fn get_validated_path(
     if !absolute_path.try_exists()? {
         return Err(anyhow!(format!(
             "File does not exist. Maybe file path is not under astronote `root`. Hint: root: {}, path: {}", 
-            root.to_str().unwrap(), 
-            path.to_str().unwrap(),
+            root.display(),
+            path.display(),
         )))
     }
-    let path = absolute_path.strip_prefix(&root)?;
+    let path = absolute_path.strip_prefix(root)?;
     Ok(PathBuf::from(path))
 }
 
+// Validate a path read from a table: a file under root, given relative to it.
+fn get_validated_row_path(
+    path: &str,
+    root: &Path,
+) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() || path.components().any(|component| component == Component::ParentDir) {
+        return Err(anyhow!("path must be relative to root and must not contain `..`"));
+    }
+    let absolute_path = root.join(path);
+    if !absolute_path.is_file() {
+        return Err(anyhow!("not found under root"));
+    }
+    get_validated_path(&absolute_path, root).with_context(|| "resolves to a file outside root")
+}
+
+// Validate a path which does not exist yet: its parent directory must be under root.
+fn get_validated_destination(
+    path: &Path,
+    root: &Path,
+) -> Result<PathBuf> {
+    if path.exists() {
+        return Err(anyhow!("{:?} already exists", path));
+    }
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid destination: {:?}", path))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = get_validated_path(parent, root)?;
+    Ok(parent.join(file_name))
+}
+
+// Validate a directory which may not exist yet: its nearest existing ancestor must be under root.
+fn get_validated_directory(
+    path: &Path,
+    root: &Path,
+) -> Result<PathBuf> {
+    if path.exists() {
+        return get_validated_path(path, root);
+    }
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid directory: {:?}", path))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(get_validated_directory(parent, root)?.join(file_name))
+}
+
+fn print_load_errors(errors: &[(PathBuf, anyhow::Error)]) {
+    for (path, err) in errors {
+        println!("{} {:?}: {:#}", "Warning:".yellow(), path, err);
+    }
+    print_skipped(errors.len());
+}
+
+fn print_skipped(count: usize) {
+    if count == 0 {
+        return;
+    }
+    println!(
+        "{} {} {}",
+        "Skipped".yellow(),
+        count,
+        "notes which failed to load; run `astronote doctor` to inspect them".yellow()
+    );
+    println!();
+}
+
 fn input_quality(note: &Note) -> u32 {
     let input = prompt!(
         "{}",
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use astronote_core::db::Repository;
+use astronote_core::prelude::*;
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum ListOrder {
+    /// the earliest due first
+    #[default]
+    Due,
+    /// alphabetically by path
+    Path,
+}
+
+/// A note as a line of `astronote list`.
+#[derive(Debug)]
+pub struct ListItem {
+    pub path: PathBuf,
+    pub next_datetime: NaiveDateTime,
+    pub scheduler: String,
+    /// scheduler fields, e.g. `counter=1 easiness_factor=2.50 interval=1`
+    pub parameters: String,
+}
+
+impl TryFrom<Note> for ListItem {
+    type Error = anyhow::Error;
+
+    fn try_from(note: Note) -> Result<Self> {
+        let serialized_note: SerializedNote = note.try_into()?;
+        let scheduler = &serialized_note.scheduler;
+        let parameters = scheduler
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| key.as_str() != "type")
+            .map(|(key, value)| match value.as_f64() {
+                Some(number) if value.is_f64() => format!("{key}={number:.2}"),
+                _ => format!("{key}={value}"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(Self {
+            path: serialized_note.relative_path,
+            next_datetime: serialized_note.next_datetime,
+            scheduler: scheduler["type"].as_str().unwrap_or_default().to_string(),
+            parameters,
+        })
+    }
+}
+
+/// Notes under `prefix` (relative to `root`), sorted by `order`, at most `limit` of them.
+pub fn list_items(
+    notes: Vec<Note>,
+    prefix: Option<&Path>,
+    order: ListOrder,
+    limit: usize,
+) -> Result<Vec<ListItem>> {
+    let mut items = notes
+        .into_iter()
+        .filter(|note| prefix.is_none_or(|prefix| note.relative_path.starts_with(prefix)))
+        .map(ListItem::try_from)
+        .collect::<Result<Vec<_>>>()?;
+    match order {
+        ListOrder::Due => {
+            items.sort_by(|a, b| (a.next_datetime, &a.path).cmp(&(b.next_datetime, &b.path)))
+        }
+        ListOrder::Path => items.sort_by(|a, b| a.path.cmp(&b.path)),
+    }
+    items.truncate(limit);
+    Ok(items)
+}
+
+/// Due date relative to `now`, e.g. "in 3d" or "overdue 2h".
+pub fn relative_due(next_datetime: &NaiveDateTime, now: &NaiveDateTime) -> String {
+    let difference = *next_datetime - *now;
+    let span = difference.abs();
+    let span = if span.num_days() > 0 {
+        format!("{}d", span.num_days())
+    } else if span.num_hours() > 0 {
+        format!("{}h", span.num_hours())
+    } else if span.num_minutes() > 0 {
+        format!("{}m", span.num_minutes())
+    } else {
+        return "now".to_string();
+    };
+    if difference < Duration::zero() {
+        format!("overdue {span}")
+    } else {
+        format!("in {span}")
+    }
+}
+
+/// Numbers of notes by due date, for `astronote status`.
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    pub due_now: usize,
+    /// by the end of today, including those due now
+    pub due_today: usize,
+    /// by the end of the 7 days starting today
+    pub due_this_week: usize,
+    pub total: usize,
+}
+
+impl Status {
+    /// Count the notes from the due-date index, without loading them.
+    pub fn of(repo: &dyn Repository, now: &NaiveDateTime) -> Result<Self> {
+        let end_of_today = now.date().and_time(NaiveTime::MIN) + Duration::days(1);
+        let end_of_week = end_of_today + Duration::days(6);
+        let entries = repo.get_due(&NaiveDateTime::MAX, usize::MAX)?;
+        let count = |before: &NaiveDateTime| {
+            entries
+                .iter()
+                .filter(|entry| entry.next_datetime <= *before)
+                .count()
+        };
+        Ok(Self {
+            due_now: count(now),
+            due_today: count(&end_of_today),
+            due_this_week: count(&end_of_week),
+            total: entries.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use astronote_core::db::memory::MemoryRepository;
+
+    #[test]
+    fn describe_due_dates() {
+        let now =
+            NaiveDateTime::parse_from_str("2024-01-10 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let relative = |hours: i64| relative_due(&(now + Duration::hours(hours)), &now);
+        assert_eq!(relative(0), "now");
+        assert_eq!(relative(3), "in 3h");
+        assert_eq!(relative(72), "in 3d");
+        assert_eq!(relative(-50), "overdue 2d");
+    }
+
+    #[test]
+    fn list_and_count_notes() {
+        let now = chrono::Local::now().naive_local();
+        let note = |path: &str, days: i64| {
+            let mut note = Note::new_default(path);
+            note.next_datetime = now + Duration::days(days);
+            note
+        };
+        let repo = MemoryRepository::new();
+        repo.create(vec![
+            note("rust/b.md", -1),
+            note("rust/a.md", 3),
+            note("go/c.md", 0),
+            note("later.md", 30),
+        ])
+        .unwrap();
+
+        let items = list_items(repo.get_all().unwrap(), None, ListOrder::Due, 2).unwrap();
+        let pathes = items
+            .iter()
+            .map(|item| item.path.as_path())
+            .collect::<Vec<_>>();
+        assert_eq!(pathes, [Path::new("rust/b.md"), Path::new("go/c.md")]);
+        let items = list_items(
+            repo.get_all().unwrap(),
+            Some(Path::new("rust")),
+            ListOrder::Path,
+            usize::MAX,
+        )
+        .unwrap();
+        let pathes = items
+            .iter()
+            .map(|item| item.path.as_path())
+            .collect::<Vec<_>>();
+        assert_eq!(pathes, [Path::new("rust/a.md"), Path::new("rust/b.md")]);
+        assert_eq!(items[0].scheduler, "SuperMemo2");
+        assert!(items[0].parameters.contains("easiness_factor=2.50"));
+
+        let status = Status::of(&repo, &now).unwrap();
+        assert_eq!(status.due_now, 2);
+        assert_eq!(status.due_this_week, 3);
+        assert_eq!(status.total, 4);
+    }
+}
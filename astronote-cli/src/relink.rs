@@ -0,0 +1,150 @@
+use anyhow::Result;
+use astronote_core::fingerprint::{fingerprint, inode};
+use astronote_core::Note;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::files::walk_files;
+
+/// Orphaned notes matched to files which were moved or renamed under `root`.
+#[derive(Debug, Default)]
+pub struct RelinkPlan {
+    /// (old path, new path) relative to `root`
+    pub relinks: Vec<(PathBuf, PathBuf)>,
+    /// like `relinks`, but matched by inode alone; inodes are reused,
+    /// so these should be confirmed before they are applied
+    pub guessed: Vec<(PathBuf, PathBuf)>,
+    /// orphaned notes without any matching file
+    pub unmatched: Vec<PathBuf>,
+    /// orphaned notes matching more than one file
+    pub ambiguous: Vec<(PathBuf, Vec<PathBuf>)>,
+}
+
+struct Candidate {
+    relative_path: PathBuf,
+    fingerprint: Option<String>,
+    inode: Option<u64>,
+}
+
+/// Match notes whose file no longer exists to untracked files under `root`.
+///
+/// Candidates are the files [`walk_files`] lists, so ignored files are never relinked.
+/// A file matches when its content fingerprint is unchanged. Failing that, a file
+/// with the same inode (i.e. it was moved and then edited) is a guess.
+pub fn plan_relink(notes: &[Note], root: &Path, excluded: &[PathBuf]) -> Result<RelinkPlan> {
+    let mut plan = RelinkPlan::default();
+    let orphans = notes
+        .iter()
+        .filter(|note| !root.join(&note.relative_path).exists())
+        .collect::<Vec<_>>();
+    if orphans.is_empty() {
+        return Ok(plan);
+    }
+
+    let tracked = notes
+        .iter()
+        .map(|note| PathBuf::from(&note.relative_path))
+        .collect::<HashSet<_>>();
+    let candidates = walk_files(root, excluded)?
+        .into_iter()
+        .filter_map(|path| {
+            let relative_path = path.strip_prefix(root).ok()?.to_path_buf();
+            if tracked.contains(&relative_path) {
+                return None;
+            }
+            Some(Candidate {
+                relative_path,
+                fingerprint: fingerprint(&path).ok(),
+                inode: inode(&path),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut claimed = HashSet::new();
+    for orphan in orphans {
+        let orphan_path = PathBuf::from(&orphan.relative_path);
+        let unclaimed = |candidate: &&Candidate| !claimed.contains(&candidate.relative_path);
+        let mut matches = candidates
+            .iter()
+            .filter(unclaimed)
+            .filter(|c| orphan.fingerprint.is_some() && c.fingerprint == orphan.fingerprint)
+            .collect::<Vec<_>>();
+        let guessed = matches.is_empty();
+        if guessed {
+            matches = candidates
+                .iter()
+                .filter(unclaimed)
+                .filter(|c| orphan.inode.is_some() && c.inode == orphan.inode)
+                .collect();
+        }
+        match matches.as_slice() {
+            [] => plan.unmatched.push(orphan_path),
+            [candidate] => {
+                claimed.insert(candidate.relative_path.clone());
+                let relink = (orphan_path, candidate.relative_path.clone());
+                if guessed {
+                    plan.guessed.push(relink);
+                } else {
+                    plan.relinks.push(relink);
+                }
+            }
+            _ => {
+                let pathes = matches.iter().map(|c| c.relative_path.clone()).collect();
+                plan.ambiguous.push((orphan_path, pathes));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn relink_moved_file() {
+        let dir = TempDir::new("relink").unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.md"), "a").unwrap();
+        fs::write(root.join("b.md"), "b").unwrap();
+        let notes = ["a.md", "b.md"]
+            .into_iter()
+            .map(|path| {
+                let mut note = Note::new_default(path);
+                note.refresh_fingerprint(&root.join(path)).unwrap();
+                note
+            })
+            .collect::<Vec<_>>();
+        fs::create_dir(root.join("moved")).unwrap();
+        fs::rename(root.join("a.md"), root.join("moved/a.md")).unwrap();
+        fs::remove_file(root.join("b.md")).unwrap();
+
+        let plan = plan_relink(&notes, root, &[]).unwrap();
+        assert_eq!(
+            plan.relinks,
+            vec![(PathBuf::from("a.md"), PathBuf::from("moved/a.md"))]
+        );
+        assert_eq!(plan.unmatched, vec![PathBuf::from("b.md")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn guess_edited_file_by_inode() {
+        let dir = TempDir::new("relink").unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.md"), "a").unwrap();
+        let mut note = Note::new_default("a.md");
+        note.refresh_fingerprint(&root.join("a.md")).unwrap();
+        fs::rename(root.join("a.md"), root.join("b.md")).unwrap();
+        fs::write(root.join("b.md"), "edited").unwrap();
+
+        let plan = plan_relink(&[note], root, &[]).unwrap();
+        assert!(plan.relinks.is_empty());
+        assert_eq!(
+            plan.guessed,
+            vec![(PathBuf::from("a.md"), PathBuf::from("b.md"))]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use astronote_core::Note;
+use globset::{GlobSet, GlobSetBuilder};
+
+use crate::files::glob;
+use std::path::{Component, Path, PathBuf};
+
+/// Which tracked notes `astronote remove` applies to.
+#[derive(Debug)]
+pub struct RemoveTargets {
+    /// tracked paths and directories, relative to `root`
+    pathes: Vec<PathBuf>,
+    /// glob patterns, relative to `root`
+    globs: GlobSet,
+    /// notes whose file no longer exists
+    missing: bool,
+}
+
+impl RemoveTargets {
+    /// Targets from command line arguments given relative to `current_dir`.
+    ///
+    /// Arguments with glob characters (`*`, `?`, `[`, `{`) are patterns (see [`glob`])
+    /// matched against tracked notes, so they also match notes whose files were deleted.
+    pub fn new(args: &[PathBuf], root: &Path, current_dir: &Path, missing: bool) -> Result<Self> {
+        let mut pathes = vec![];
+        let mut globs = GlobSetBuilder::new();
+        for arg in args {
+            let relative_path = relative_to_root(&current_dir.join(arg), root)?;
+            let is_glob = arg
+                .to_str()
+                .is_some_and(|arg| arg.contains(['*', '?', '[', '{']));
+            if is_glob {
+                let pattern = relative_path
+                    .to_str()
+                    .ok_or(anyhow!("{arg:?} is not valid UTF-8"))?;
+                globs.add(glob(pattern)?);
+            } else {
+                pathes.push(relative_path);
+            }
+        }
+        Ok(Self {
+            pathes,
+            globs: globs.build()?,
+            missing,
+        })
+    }
+
+    /// Notes matching any of the targets, in the order of `notes`; each note is
+    /// selected at most once, however many targets it matches.
+    pub fn select(&self, notes: Vec<Note>, root: &Path) -> Vec<Note> {
+        notes
+            .into_iter()
+            .filter(|note| {
+                let path = &note.relative_path;
+                self.pathes.iter().any(|target| path.starts_with(target))
+                    || self.globs.is_match(path)
+                    || (self.missing && !root.join(path).exists())
+            })
+            .collect()
+    }
+}
+
+// The file may have been deleted, so the path is normalized without touching the
+// filesystem; only its parent is resolved to follow symlinks where possible.
+fn relative_to_root(path: &Path, root: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    let relative_path = normalized
+        .strip_prefix(root)
+        .ok()
+        .map(Path::to_path_buf)
+        .or_else(|| {
+            let parent = normalized.parent()?.canonicalize().ok()?;
+            let relative_parent = parent.strip_prefix(root).ok()?;
+            Some(relative_parent.join(normalized.file_name()?))
+        })
+        .ok_or(anyhow!("{path:?} is not under astronote `root`: {root:?}"))?;
+    Ok(relative_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select_notes_to_remove() {
+        let root = Path::new("/notes");
+        let notes = || {
+            ["rust/a.md", "rust/nested/b.md", "go/c.md", "d.md"]
+                .into_iter()
+                .map(Note::new_default)
+                .collect::<Vec<_>>()
+        };
+        let selected = |args: &[&str], current_dir: &str| {
+            let args = args.iter().map(PathBuf::from).collect::<Vec<_>>();
+            RemoveTargets::new(&args, root, Path::new(current_dir), false)
+                .unwrap()
+                .select(notes(), root)
+                .into_iter()
+                .map(|note| note.relative_path)
+                .collect::<Vec<_>>()
+        };
+        // a directory, and a file given relative to the working directory
+        assert_eq!(
+            selected(&["../rust", "../d.md"], "/notes/go"),
+            ["rust/a.md", "rust/nested/b.md", "d.md"].map(PathBuf::from)
+        );
+        // `*` stays within a directory
+        assert_eq!(
+            selected(&["rust/*.md"], "/notes"),
+            [PathBuf::from("rust/a.md")]
+        );
+        assert_eq!(selected(&["**/b.md", "*.md"], "/notes").len(), 2);
+        assert!(RemoveTargets::new(&[PathBuf::from("/elsewhere")], root, root, false).is_err());
+
+        // every note of this test is missing its file
+        let targets = RemoveTargets::new(&[], root, root, true).unwrap();
+        assert_eq!(targets.select(notes(), root).len(), 4);
+    }
+}
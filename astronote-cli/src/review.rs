@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Context, Result};
+use astronote_core::db::Repository;
+use astronote_core::Note;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// A note as `astronote next --json` and `astronote grade --json` print it.
+#[derive(Debug, Serialize)]
+pub struct ReviewItem {
+    #[serde(serialize_with = "astronote_core::encoded_path::serialize")]
+    pub path: PathBuf,
+    #[serde(serialize_with = "astronote_core::encoded_path::serialize")]
+    pub absolute_path: PathBuf,
+    pub next_datetime: NaiveDateTime,
+    /// number of reviews so far; 0 for a new note
+    pub reviews: u64,
+}
+
+impl ReviewItem {
+    pub fn new(note: &Note, root: &Path) -> Self {
+        Self {
+            path: note.relative_path.clone(),
+            absolute_path: root.join(&note.relative_path),
+            next_datetime: note.next_datetime,
+            reviews: note.review_count(),
+        }
+    }
+}
+
+/// The first note due before `before` which can be reviewed, like the `review` loop picks them.
+///
+/// Broken notes and notes with an unsupported scheduler are skipped, and so are
+/// new notes once `new_left` is 0.
+pub fn next_note(
+    repo: &dyn Repository,
+    before: &NaiveDateTime,
+    new_left: usize,
+) -> Result<Option<Note>> {
+    let entries = repo
+        .get_due(before, usize::MAX)
+        .with_context(|| "Failed to retreive due notes")?;
+    for entry in entries {
+        let Ok(note) = repo.get_one(&entry.relative_path) else {
+            continue;
+        };
+        let is_new = note.reviews.is_empty();
+        if note.scheduler.unsupported().is_none() && !(is_new && new_left == 0) {
+            return Ok(Some(note));
+        }
+    }
+    Ok(None)
+}
+
+/// Record a review of `quality` and refresh the fingerprint of the note's file at `absolute_path`.
+pub fn grade(note: &mut Note, absolute_path: &Path, quality: u8) -> Result<()> {
+    if let Some(unsupported) = note.scheduler.unsupported() {
+        return Err(anyhow!(
+            "{}: unsupported scheduler `{}`",
+            note.relative_path.display(),
+            unsupported.type_name()
+        ));
+    }
+    note.refresh_fingerprint(absolute_path)?;
+    note.review(quality);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use astronote_core::db::memory::MemoryRepository;
+    use astronote_core::SerializedNote;
+    use chrono::Duration;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn grade_next_note() {
+        let dir = TempDir::new("review").unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.md"), "a").unwrap();
+        fs::write(root.join("b.md"), "b").unwrap();
+        let mut reviewed = Note::new_default("b.md");
+        reviewed.review(4);
+        reviewed.next_datetime -= Duration::days(10);
+        // overdue, but with a scheduler of a newer astronote
+        let mut unsupported: SerializedNote = Note::new_default("c.md").try_into().unwrap();
+        unsupported.scheduler = serde_json::json!({ "type": "Unknown" });
+        unsupported.next_datetime -= Duration::days(20);
+        let repo = MemoryRepository::from_serialized(vec![unsupported]);
+        repo.create(vec![Note::new_default("a.md"), reviewed])
+            .unwrap();
+        let now = chrono::Local::now().naive_local();
+
+        let mut note = next_note(&repo, &now, 0).unwrap().unwrap();
+        assert_eq!(note.relative_path, Path::new("b.md"));
+        grade(&mut note, &root.join("b.md"), 5).unwrap();
+        repo.update(vec![note]).unwrap();
+        // new notes are left for later once the daily limit is reached
+        assert!(next_note(&repo, &now, 0).unwrap().is_none());
+        let mut note = next_note(&repo, &now, 1).unwrap().unwrap();
+        assert_eq!(note.relative_path, Path::new("a.md"));
+
+        grade(&mut note, &root.join("a.md"), 5).unwrap();
+        repo.update(vec![note]).unwrap();
+        let note = repo.get_one(Path::new("a.md")).unwrap();
+        assert!(note.fingerprint.is_some());
+        let item = ReviewItem::new(&note, root);
+        assert_eq!(item.reviews, 1);
+        assert!(item.next_datetime > now);
+        assert_eq!(item.absolute_path, root.join("a.md"));
+    }
+}
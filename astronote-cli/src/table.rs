@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use astronote_core::prelude::*;
+use astronote_core::schedulers::sm2::SuperMemo2;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl TableFormat {
+    /// Format of `path` judging from its extension (default: CSV).
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "tsv" => Self::Tsv,
+            _ => Self::Csv,
+        }
+    }
+
+    fn delimiter(&self) -> u8 {
+        match self {
+            Self::Csv => b',',
+            Self::Tsv => b'\t',
+        }
+    }
+}
+
+/// A note as a row of the table.
+///
+/// Scheduler fields which the scheduler of the note does not have are left empty.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Row {
+    pub path: String,
+    pub next_datetime: String,
+    pub scheduler: String,
+    pub counter: Option<i64>,
+    pub interval: Option<i64>,
+    pub easiness_factor: Option<f64>,
+    /// number of reviews; informational only, it is ignored on import
+    pub review_count: Option<u64>,
+}
+
+impl TryFrom<Note> for Row {
+    type Error = anyhow::Error;
+
+    fn try_from(note: Note) -> Result<Self> {
+        let serialized_note: SerializedNote = note.try_into()?;
+        let scheduler = &serialized_note.scheduler;
+        let path = serialized_note.relative_path.to_str().ok_or(anyhow!(
+            "{:?} is not valid UTF-8 and cannot be written to a table",
+            serialized_note.relative_path
+        ))?;
+        Ok(Self {
+            path: path.to_string(),
+            next_datetime: serialized_note
+                .next_datetime
+                .format(DATETIME_FORMAT)
+                .to_string(),
+            scheduler: scheduler["type"].as_str().unwrap_or_default().to_string(),
+            counter: scheduler["counter"].as_i64(),
+            interval: scheduler["interval"].as_i64(),
+            easiness_factor: scheduler["easiness_factor"].as_f64(),
+            review_count: Some(
+                serialized_note.dropped_reviews + serialized_note.reviews.len() as u64,
+            ),
+        })
+    }
+}
+
+impl Row {
+    /// Apply the row to `note`, or create a new note if `None`.
+    ///
+    /// The due date is replaced, and so are the scheduler fields given in the row.
+    pub fn apply(&self, note: Option<Note>) -> Result<Note> {
+        let next_datetime = parse_datetime(&self.next_datetime)?;
+        let note = note.unwrap_or_else(|| Note::new_default(&self.path));
+        let mut serialized_note: SerializedNote = note.try_into()?;
+        // start over from the defaults if the scheduler type changes
+        if serialized_note.scheduler["type"] != self.scheduler.as_str() {
+            serialized_note.scheduler = match self.scheduler.as_str() {
+                "SuperMemo2" => {
+                    let scheduler: Box<dyn SchedulingAlgorithm> = Box::<SuperMemo2>::default();
+                    serde_json::to_value(scheduler)?
+                }
+                name => serde_json::json!({ "type": name }),
+            };
+        }
+        let scheduler = serialized_note
+            .scheduler
+            .as_object_mut()
+            .ok_or(anyhow!("Scheduler of {} is not an object", self.path))?;
+        if let Some(counter) = self.counter {
+            scheduler.insert("counter".to_string(), counter.into());
+        }
+        if let Some(interval) = self.interval {
+            scheduler.insert("interval".to_string(), interval.into());
+        }
+        if let Some(easiness_factor) = self.easiness_factor {
+            scheduler.insert("easiness_factor".to_string(), easiness_factor.into());
+        }
+        serialized_note.next_datetime = next_datetime;
+        let note: Note = serialized_note
+            .try_into()
+            .with_context(|| format!("Invalid scheduler for {}", self.path))?;
+        if note.scheduler.unsupported().is_some() {
+            return Err(anyhow!("Unknown scheduler `{}`", self.scheduler));
+        }
+        Ok(note)
+    }
+}
+
+/// Split off notes whose paths cannot be written to a table as they are not valid UTF-8.
+pub fn split_unwritable(notes: Vec<Note>) -> (Vec<Note>, Vec<PathBuf>) {
+    let (writable, unwritable): (Vec<_>, Vec<_>) = notes
+        .into_iter()
+        .partition(|note| note.relative_path.to_str().is_some());
+    let unwritable = unwritable
+        .into_iter()
+        .map(|note| note.relative_path)
+        .collect();
+    (writable, unwritable)
+}
+
+pub fn write_table<W: Write>(notes: Vec<Note>, writer: W, format: TableFormat) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(format.delimiter())
+        .from_writer(writer);
+    for note in notes {
+        let row: Row = note.try_into()?;
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_table<R: Read>(reader: R, format: TableFormat) -> Result<Vec<Row>> {
+    csv::ReaderBuilder::new()
+        .delimiter(format.delimiter())
+        .from_reader(reader)
+        .deserialize()
+        .enumerate()
+        // the header is line 1
+        .map(|(i, row)| row.with_context(|| format!("Invalid row at line {}", i + 2)))
+        .collect()
+}
+
+// Accept what spreadsheets commonly produce, as well as the exported format.
+fn parse_datetime(text: &str) -> Result<chrono::NaiveDateTime> {
+    let text = text.trim();
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|format| chrono::NaiveDateTime::parse_from_str(text, format).ok())
+    .or_else(|| {
+        chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.and_time(chrono::NaiveTime::MIN))
+    })
+    .ok_or(anyhow!("Invalid datetime: {text}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn export_and_import_table() {
+        let mut note = Note::new_default("a.md");
+        note.review(5);
+        let mut buf = vec![];
+        write_table(vec![note], &mut buf, TableFormat::Tsv).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("path\tnext_datetime\tscheduler\tcounter"));
+
+        let edited = text.replace("\t1\t1\t2.5\t1", "\t4\t10\t2.5\t1");
+        let rows = read_table(edited.as_bytes(), TableFormat::Tsv).unwrap();
+        assert_eq!(rows.len(), 1);
+        let mut note = Note::new_default("a.md");
+        note.review(5);
+        let note = rows[0].apply(Some(note)).unwrap();
+        let row: Row = note.try_into().unwrap();
+        assert_eq!((row.counter, row.interval), (Some(4), Some(10)));
+        assert_eq!(row.review_count, Some(1));
+    }
+
+    #[test]
+    fn import_new_note() {
+        let table = "path,next_datetime,scheduler,counter,interval,easiness_factor,review_count\n\
+                     b.md,2030-01-02,SuperMemo2,,,1.8,\n\
+                     c.md,2030-01-02,Unknown,,,,\n";
+        let rows = read_table(table.as_bytes(), TableFormat::Csv).unwrap();
+        let note = rows[0].apply(None).unwrap();
+        let row: Row = note.try_into().unwrap();
+        assert_eq!(row.next_datetime, "2030-01-02 00:00:00");
+        assert_eq!(row.counter, Some(0));
+        assert_eq!(row.easiness_factor, Some(1.8));
+        assert!(rows[1].apply(None).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skip_non_utf8_path() {
+        // "café.md" in Latin-1
+        let latin1 = astronote_core::encoded_path::from_bytes(b"caf\xe9.md".to_vec());
+        let notes = vec![Note::new_default(&latin1), Note::new_default("a.md")];
+        let (notes, unwritable) = split_unwritable(notes);
+        assert_eq!(notes.len(), 1);
+        assert_eq!(unwritable, [latin1]);
+        let mut buf = vec![];
+        write_table(notes, &mut buf, TableFormat::Csv).unwrap();
+        assert!(String::from_utf8(buf).unwrap().contains("a.md"));
+    }
+}
@@ -0,0 +1,525 @@
+//! Storage of scheduling state in the front matter of the notes themselves.
+//!
+//! The state is kept in a single `astronote` entry whose value is JSON, which YAML
+//! front matter (`---`) accepts as it is and TOML front matter (`+++`) as a string.
+//! Everything else in the file is left as it is, and since the state travels with
+//! the file, renaming a note needs no bookkeeping.
+
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::db::index::{Index, IndexEntry};
+use crate::db::{
+    write_atomically, ConflictError, LoadReport, NotFoundError, Repository, LOCK_FILE_NAME,
+};
+use crate::{migration, Note, SerializedNote};
+
+const KEY: &str = "astronote";
+const NOTE_EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+pub struct FrontMatterRepository {
+    root: PathBuf,
+    /// directories relative to `root` whose notes are not ours
+    excluded: Vec<PathBuf>,
+}
+
+impl FrontMatterRepository {
+    /// Notes are markdown files under `root`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            excluded: vec![],
+        }
+    }
+
+    /// Leave out notes under `dirs` (relative to `root`), e.g. the roots of other collections.
+    pub fn excluding(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.excluded = dirs;
+        self
+    }
+
+    fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.excluded
+            .iter()
+            .any(|dir| relative_path.starts_with(dir))
+    }
+
+    fn read_serialized(&self, relative_path: &Path) -> Result<Option<SerializedNote>> {
+        let path = self.root.join(relative_path);
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        let Some(mut state) =
+            read_state(&text).with_context(|| format!("Invalid front matter in {path:?}"))?
+        else {
+            return Ok(None);
+        };
+        // the path is not stored; it is set below, as it need not be valid UTF-8
+        state
+            .as_object_mut()
+            .ok_or(anyhow!(
+                "Invalid front matter in {path:?}: `{KEY}` is not an object"
+            ))?
+            .insert("relative_path".to_string(), "".into());
+        let mut serialized_note: SerializedNote = serde_json::from_value(state)
+            .with_context(|| format!("Invalid front matter in {path:?}"))?;
+        serialized_note.relative_path = relative_path.to_path_buf();
+        if serialized_note.schema_version > migration::SCHEMA_VERSION {
+            let err = migration::NewerSchemaError {
+                version: serialized_note.schema_version,
+            };
+            return Err(anyhow::Error::new(err).context(format!("Failed to read {path:?}")));
+        }
+        Ok(Some(serialized_note))
+    }
+
+    fn read_note(&self, relative_path: &Path) -> Result<Option<Note>> {
+        self.read_serialized(relative_path)?
+            .map(|serialized_note| {
+                serialized_note
+                    .try_into()
+                    .with_context(|| format!("Failed to deserialize note {relative_path:?}"))
+            })
+            .transpose()
+    }
+
+    fn write_note(&self, note: Note) -> Result<()> {
+        let path = self.root.join(&note.relative_path);
+        let serialized_note: SerializedNote = note
+            .try_into()
+            .with_context(|| "Failed to serialize note structure")?;
+        let mut state = serde_json::to_value(serialized_note)?;
+        if let Some(state) = state.as_object_mut() {
+            // the path is where the file is; the rest changes with the file itself
+            for key in ["relative_path", "fingerprint", "inode"] {
+                state.remove(key);
+            }
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        write_note_file(&path, &write_state(&text, &state)?)
+    }
+
+    // Take an exclusive advisory lock on the notes, like the database does;
+    // the lock file is hidden, so it is not taken for a note.
+    fn lock(&self) -> Result<File> {
+        let lock_path = self.root.join(LOCK_FILE_NAME);
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file: {lock_path:?}"))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock notes: {:?}", self.root))?;
+        Ok(file)
+    }
+
+    // Markdown files under root, relative to it. Hidden and excluded entries are skipped.
+    fn list_notes(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut notes = vec![];
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Failed to read entries of {dir:?}"))?
+        {
+            let path = entry
+                .with_context(|| format!("Failed to read entries of {dir:?}"))?
+                .path();
+            let is_hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if is_hidden || self.is_excluded(path.strip_prefix(&self.root)?) {
+                continue;
+            }
+            if path.is_dir() {
+                notes.extend(self.list_notes(&path)?);
+            } else if is_note_file(&path) {
+                notes.push(path.strip_prefix(&self.root)?.to_path_buf());
+            }
+        }
+        Ok(notes)
+    }
+}
+
+impl Repository for FrontMatterRepository {
+    fn create(&self, notes: Vec<Note>) -> Result<()> {
+        // nothing is written unless every note can be
+        if let Some(note) = notes.iter().find(|note| !is_note_file(&note.relative_path)) {
+            return Err(anyhow!(
+                "{:?} is not a markdown file; only markdown files can keep their schedule in front matter",
+                note.relative_path
+            ));
+        }
+        if let Some(note) = notes
+            .iter()
+            .find(|note| self.is_excluded(&note.relative_path))
+        {
+            return Err(anyhow!(
+                "{:?} is under the root of another collection; add it to that one",
+                note.relative_path
+            ));
+        }
+        let _lock = self.lock()?;
+        for note in notes {
+            if self.read_serialized(&note.relative_path)?.is_none() {
+                self.write_note(note)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&self, notes: Vec<Note>) -> Result<()> {
+        let _lock = self.lock()?;
+        for mut note in notes {
+            let Some(stored) = self.read_serialized(&note.relative_path)? else {
+                continue;
+            };
+            if stored.revision != note.revision {
+                return Err(ConflictError {
+                    relative_path: note.relative_path,
+                    expected: note.revision,
+                    found: stored.revision,
+                }
+                .into());
+            }
+            note.revision += 1;
+            self.write_note(note)?;
+        }
+        Ok(())
+    }
+
+    fn get_one(&self, path: &Path) -> Result<Note> {
+        self.read_note(path)?.ok_or_else(|| {
+            anyhow::Error::new(NotFoundError {
+                relative_path: path.to_path_buf(),
+            })
+            .context(format!("{path:?} has no schedule in its front matter"))
+        })
+    }
+
+    fn get_all(&self) -> Result<Vec<Note>> {
+        let notes = self
+            .list_notes(&self.root)?
+            .par_iter()
+            .map(|relative_path| self.read_note(relative_path))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(notes.into_iter().flatten().collect())
+    }
+
+    fn get_all_lenient(&self) -> Result<LoadReport> {
+        let results = self
+            .list_notes(&self.root)?
+            .into_par_iter()
+            .map(|relative_path| {
+                let result = self.read_note(&relative_path);
+                (relative_path, result)
+            })
+            .collect::<Vec<_>>();
+        let mut report = LoadReport::default();
+        for (relative_path, result) in results {
+            match result {
+                Ok(note) => report.notes.extend(note),
+                Err(err) => report.errors.push((self.root.join(relative_path), err)),
+            }
+        }
+        Ok(report)
+    }
+
+    // Every note is read; broken ones are left out like in the database index.
+    fn get_due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Result<Vec<IndexEntry>> {
+        let serialized_notes = self
+            .list_notes(&self.root)?
+            .par_iter()
+            .filter_map(|relative_path| self.read_serialized(relative_path).ok().flatten())
+            .collect::<Vec<_>>();
+        let mut index = Index::default();
+        for serialized_note in serialized_notes.iter() {
+            index.insert(serialized_note);
+        }
+        Ok(index.due(before, limit))
+    }
+
+    // Every note is read again; only reviews need this, before the first note.
+    fn get_broken(&self) -> Result<Vec<PathBuf>> {
+        let broken = self
+            .list_notes(&self.root)?
+            .into_par_iter()
+            .filter(|relative_path| self.read_serialized(relative_path).is_err())
+            .map(|relative_path| self.root.join(relative_path))
+            .collect();
+        Ok(broken)
+    }
+
+    fn delete(&self, notes: Vec<Note>) -> Result<()> {
+        let _lock = self.lock()?;
+        for note in notes {
+            let path = self.root.join(&note.relative_path);
+            let text =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            let removed = remove_state(&text)
+                .ok_or(anyhow!("{path:?} has no schedule in its front matter"))?;
+            write_note_file(&path, &removed)?;
+        }
+        Ok(())
+    }
+
+    // The schedule has moved along with the file; just make sure it has.
+    fn rename(&self, renames: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        for (from, to) in renames.iter() {
+            if self.read_serialized(to)?.is_none() {
+                return Err(anyhow!(
+                    "{to:?} has no schedule in its front matter; move {from:?} there first"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_note_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| NOTE_EXTENSIONS.iter().any(|note_ext| ext == *note_ext))
+}
+
+// Keep the permissions of the note; a new file is created to replace it atomically.
+fn write_note_file(path: &Path, text: &str) -> Result<()> {
+    let permissions = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata of {path:?}"))?
+        .permissions();
+    write_atomically(path, text.as_bytes())?;
+    fs::set_permissions(path, permissions)
+        .with_context(|| format!("Failed to set permissions of {path:?}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Syntax {
+    Yaml,
+    Toml,
+}
+
+// Location of the front matter in a file.
+struct FrontMatter {
+    syntax: Syntax,
+    /// lines between the fences
+    body: Range<usize>,
+    /// end of the closing fence, including its line break
+    end: usize,
+}
+
+fn find_front_matter(text: &str) -> Option<FrontMatter> {
+    let mut lines = line_spans(text);
+    let (_, first) = lines.next()?;
+    let syntax = match first.trim_end() {
+        "---" => Syntax::Yaml,
+        "+++" => Syntax::Toml,
+        _ => return None,
+    };
+    let fence = first.trim_end();
+    for (offset, line) in lines {
+        let line_content = line.trim_end();
+        if line_content == fence || (syntax == Syntax::Yaml && line_content == "...") {
+            return Some(FrontMatter {
+                syntax,
+                body: first.len()..offset,
+                end: offset + line.len(),
+            });
+        }
+    }
+    None
+}
+
+// Lines of `text` with their line breaks, and their offsets.
+fn line_spans(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line))
+    })
+}
+
+// The line of the `astronote` entry, and its value.
+fn find_entry<'a>(text: &'a str, front_matter: &FrontMatter) -> Option<(Range<usize>, &'a str)> {
+    let separator = match front_matter.syntax {
+        Syntax::Yaml => ':',
+        Syntax::Toml => '=',
+    };
+    line_spans(&text[front_matter.body.clone()]).find_map(|(offset, line)| {
+        let value = line
+            .strip_prefix(KEY)?
+            .trim_start()
+            .strip_prefix(separator)?;
+        let start = front_matter.body.start + offset;
+        Some((start..start + line.len(), value.trim()))
+    })
+}
+
+fn read_state(text: &str) -> Result<Option<serde_json::Value>> {
+    let Some(front_matter) = find_front_matter(text) else {
+        return Ok(None);
+    };
+    let Some((_, value)) = find_entry(text, &front_matter) else {
+        return Ok(None);
+    };
+    let json = match front_matter.syntax {
+        Syntax::Yaml => value.to_string(),
+        Syntax::Toml => parse_toml_string(value)?,
+    };
+    let state = serde_json::from_str(&json).with_context(|| format!("Invalid `{KEY}` entry"))?;
+    Ok(Some(state))
+}
+
+// Add or replace the entry, adding YAML front matter if there's none.
+fn write_state(text: &str, state: &serde_json::Value) -> Result<String> {
+    let new_line = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let json = serde_json::to_string(state)?;
+    let Some(front_matter) = find_front_matter(text) else {
+        let entry = format_entry(Syntax::Yaml, &json);
+        return Ok(format!("---{new_line}{entry}{new_line}---{new_line}{text}"));
+    };
+    let entry = format!("{}{new_line}", format_entry(front_matter.syntax, &json));
+    let range = match find_entry(text, &front_matter) {
+        Some((range, _)) => range,
+        None => front_matter.body.end..front_matter.body.end,
+    };
+    let mut text = text.to_string();
+    text.replace_range(range, &entry);
+    Ok(text)
+}
+
+// Remove the entry, and the front matter too if nothing else is left in it.
+// Returns `None` if there's no entry.
+fn remove_state(text: &str) -> Option<String> {
+    let front_matter = find_front_matter(text)?;
+    let (range, _) = find_entry(text, &front_matter)?;
+    let is_left_empty = text[front_matter.body.start..range.start].trim().is_empty()
+        && text[range.end..front_matter.body.end].trim().is_empty();
+    let mut text = text.to_string();
+    if is_left_empty {
+        text.replace_range(..front_matter.end, "");
+    } else {
+        text.replace_range(range, "");
+    }
+    Some(text)
+}
+
+fn format_entry(syntax: Syntax, json: &str) -> String {
+    match syntax {
+        Syntax::Yaml => format!("{KEY}: {json}"),
+        Syntax::Toml => {
+            let escaped = json.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{KEY} = \"{escaped}\"")
+        }
+    }
+}
+
+// Only what `format_entry` writes is accepted, besides literal strings.
+fn parse_toml_string(value: &str) -> Result<String> {
+    if let Some(literal) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return Ok(literal.to_string());
+    }
+    let quoted = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or(anyhow!("`{KEY}` is not a string"))?;
+    let mut unescaped = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(c @ ('\\' | '"')) => unescaped.push(c),
+            other => return Err(anyhow!("Unsupported escape in `{KEY}`: \\{other:?}")),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    const TMPDIR_PATH: &str = "target/test-front-matter";
+
+    #[test]
+    fn keep_state_in_front_matter() {
+        let root = PathBuf::from(TMPDIR_PATH);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let plain = "# Plain\n\nbody\n";
+        let yaml = "---\r\ntitle: \"A\"\r\ntags: [a]\r\n---\r\n# A\r\n";
+        let toml = "+++\ntitle = \"B\"\n+++\nbody\n";
+        fs::write(root.join("plain.md"), plain).unwrap();
+        fs::write(root.join("sub/yaml.md"), yaml).unwrap();
+        fs::write(root.join("toml.markdown"), toml).unwrap();
+        fs::write(root.join("other.txt"), "text").unwrap();
+        let repo = FrontMatterRepository::new(&root);
+        let notes = ["plain.md", "sub/yaml.md", "toml.markdown"]
+            .into_iter()
+            .map(Note::new_default)
+            .collect();
+        repo.create(notes).unwrap();
+        // nothing is written if any of the files is not markdown
+        fs::write(root.join("new.md"), plain).unwrap();
+        let notes = vec![Note::new_default("new.md"), Note::new_default("other.txt")];
+        assert!(repo.create(notes).is_err());
+        assert_eq!(fs::read_to_string(root.join("new.md")).unwrap(), plain);
+        fs::remove_file(root.join("new.md")).unwrap();
+
+        let text = fs::read_to_string(root.join("plain.md")).unwrap();
+        assert!(text.starts_with("---\nastronote: {"));
+        assert!(text.ends_with("\n---\n# Plain\n\nbody\n"));
+        let text = fs::read_to_string(root.join("sub/yaml.md")).unwrap();
+        assert!(text.starts_with("---\r\ntitle: \"A\"\r\ntags: [a]\r\nastronote: {"));
+        assert!(text.ends_with("}\r\n---\r\n# A\r\n"));
+        let text = fs::read_to_string(root.join("toml.markdown")).unwrap();
+        assert!(text.starts_with("+++\ntitle = \"B\"\nastronote = \"{\\\""));
+
+        let mut note = repo.get_one(Path::new("toml.markdown")).unwrap();
+        note.review(5);
+        repo.update(vec![note]).unwrap();
+        let note = repo.get_one(Path::new("toml.markdown")).unwrap();
+        assert_eq!((note.revision, note.reviews.len()), (1, 1));
+        assert_eq!(repo.get_all().unwrap().len(), 3);
+        let due = repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap();
+        assert_eq!(
+            due.last().unwrap().relative_path,
+            Path::new("toml.markdown")
+        );
+
+        // the schedule moves with the file
+        fs::rename(root.join("sub/yaml.md"), root.join("moved.md")).unwrap();
+        let renames = vec![(PathBuf::from("sub/yaml.md"), PathBuf::from("moved.md"))];
+        repo.rename(renames).unwrap();
+        assert!(repo.get_one(Path::new("moved.md")).is_ok());
+
+        // deleting restores the files
+        repo.delete(repo.get_all().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("plain.md")).unwrap(), plain);
+        assert_eq!(fs::read_to_string(root.join("moved.md")).unwrap(), yaml);
+        assert_eq!(
+            fs::read_to_string(root.join("toml.markdown")).unwrap(),
+            toml
+        );
+        remove_dir_all(TMPDIR_PATH).unwrap();
+    }
+
+    #[test]
+    fn exclude_roots_of_other_collections() {
+        // not under `TMPDIR_PATH`, which the test above removes
+        let root = PathBuf::from("target/test-front-matter-excluded");
+        fs::create_dir_all(root.join("wiki")).unwrap();
+        fs::write(root.join("a.md"), "# A\n").unwrap();
+        fs::write(root.join("wiki/b.md"), "# B\n").unwrap();
+        let notes = ["a.md", "wiki/b.md"].map(Note::new_default).into();
+        FrontMatterRepository::new(&root).create(notes).unwrap();
+
+        let repo = FrontMatterRepository::new(&root).excluding(vec![PathBuf::from("wiki")]);
+        let due = repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].relative_path, Path::new("a.md"));
+        fs::write(root.join("wiki/c.md"), "# C\n").unwrap();
+        assert!(repo.create(vec![Note::new_default("wiki/c.md")]).is_err());
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Due-date index of a database.
+//!
+//! The index maps each note path to the fields needed to build a review queue,
+//! so that the queue can be computed without reading every metadata file.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::db::write_atomically;
+use crate::SerializedNote;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    entries: BTreeMap<IndexKey, IndexRecord>,
+    /// metadata files which could not be read, relative to the database directory
+    #[serde(default)]
+    broken: BTreeSet<IndexKey>,
+    /// directories holding metadata files, relative to the database directory;
+    /// their modification times tell whether the index is out of date
+    #[serde(default)]
+    directories: BTreeSet<IndexKey>,
+}
+
+// Path of a note as a key of the index; see [`crate::encoded_path`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct IndexKey(#[serde(with = "crate::encoded_path")] PathBuf);
+
+impl Borrow<Path> for IndexKey {
+    fn borrow(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexRecord {
+    next_datetime: chrono::NaiveDateTime,
+    scheduler: String,
+}
+
+/// Indexed state of a single note.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub relative_path: PathBuf,
+    pub next_datetime: chrono::NaiveDateTime,
+    /// type name of the scheduling algorithm (e.g. `SuperMemo2`)
+    pub scheduler: String,
+}
+
+impl Index {
+    pub fn load(path: &Path) -> Result<Self> {
+        let ron_string = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read index file: {path:?}"))?;
+        ron::from_str(&ron_string).with_context(|| format!("Failed to parse index file: {path:?}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let ron = ron::ser::to_string(self).with_context(|| "Failed to serialize index")?;
+        write_atomically(path, ron.as_bytes())?;
+        // moving the file into place touches its directory; keep the index as new as that,
+        // taking the time from the file system as its clock may lag behind the system's
+        let directory = path.parent().unwrap_or(Path::new("."));
+        fs::metadata(directory)
+            .and_then(|metadata| metadata.modified())
+            .and_then(|modified| {
+                File::options()
+                    .write(true)
+                    .open(path)?
+                    .set_modified(modified)
+            })
+            .with_context(|| format!("Failed to set modification time of {path:?}"))
+    }
+
+    pub fn insert(&mut self, note: &SerializedNote) {
+        let scheduler = note
+            .scheduler
+            .get("type")
+            .and_then(|name| name.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let record = IndexRecord {
+            next_datetime: note.next_datetime,
+            scheduler,
+        };
+        self.entries
+            .insert(IndexKey(note.relative_path.clone()), record);
+    }
+
+    pub fn remove(&mut self, relative_path: &Path) {
+        self.entries.remove(relative_path);
+    }
+
+    /// Record a metadata file which could not be read.
+    pub fn insert_broken(&mut self, metadata_path: PathBuf) {
+        self.broken.insert(IndexKey(metadata_path));
+    }
+
+    /// Metadata files which could not be read, relative to the database directory.
+    pub fn broken(&self) -> impl Iterator<Item = &Path> {
+        self.broken
+            .iter()
+            .map(|IndexKey(metadata_path)| metadata_path.as_path())
+    }
+
+    /// Record a directory holding metadata files, together with its ancestors.
+    pub fn insert_directory(&mut self, directory: &Path) {
+        for ancestor in directory.ancestors() {
+            self.directories.insert(IndexKey(ancestor.to_path_buf()));
+        }
+    }
+
+    /// Directories holding metadata files, relative to the database directory.
+    pub fn directories(&self) -> impl Iterator<Item = &Path> {
+        self.directories
+            .iter()
+            .map(|IndexKey(directory)| directory.as_path())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries due at or before `before`, sorted by due date.
+    pub fn due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Vec<IndexEntry> {
+        let mut due = self
+            .entries
+            .iter()
+            .filter(|(_, record)| record.next_datetime <= *before)
+            .map(|(IndexKey(relative_path), record)| IndexEntry {
+                relative_path: relative_path.clone(),
+                next_datetime: record.next_datetime,
+                scheduler: record.scheduler.clone(),
+            })
+            .collect::<Vec<_>>();
+        due.sort_by_key(|entry| entry.next_datetime);
+        due.truncate(limit);
+        due
+    }
+}
@@ -0,0 +1,322 @@
+//! Storage in an embedded transactional key-value store (redb).
+//!
+//! Notes are stored as JSON under the bytes of their path, and a second table keyed by
+//! `(next_datetime, path)` serves the review queue in due order.
+//! Every operation runs in a single transaction, so it either applies to all the
+//! given notes or to none of them.
+
+use anyhow::{anyhow, Context, Result};
+use redb::{Database, ReadableTable, TableDefinition};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::db::index::IndexEntry;
+use crate::db::{
+    deserialize_note, serialize_note, ConflictError, LoadReport, NotFoundError, Repository,
+};
+use crate::encoded_path::{from_bytes, to_bytes};
+use crate::{migration, Note, SerializedNote};
+
+/// File name of the store inside the database directory.
+pub const STORE_FILE_NAME: &str = "astronote.redb";
+
+const NOTES: TableDefinition<&[u8], &str> = TableDefinition::new("notes");
+const DUE: TableDefinition<(i64, &[u8]), ()> = TableDefinition::new("due");
+
+pub struct KeyValueRepository {
+    database: Database,
+}
+
+impl KeyValueRepository {
+    /// Open the store in `database_root`, creating it if it does not exist.
+    ///
+    /// Only one process can open the store at a time.
+    pub fn new(database_root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(database_root)
+            .with_context(|| format!("Failed to create database directory in {database_root:?}"))?;
+        let path = database_root.join(STORE_FILE_NAME);
+        let database =
+            Database::create(&path).with_context(|| format!("Failed to open {path:?}"))?;
+        // create the tables so that readers can open them
+        let transaction = database.begin_write()?;
+        transaction.open_table(NOTES)?;
+        transaction.open_table(DUE)?;
+        transaction.commit()?;
+        Ok(Self { database })
+    }
+}
+
+impl Repository for KeyValueRepository {
+    fn create(&self, notes: Vec<Note>) -> Result<()> {
+        let transaction = self.database.begin_write()?;
+        {
+            let mut table = transaction.open_table(NOTES)?;
+            let mut due = transaction.open_table(DUE)?;
+            for note in notes {
+                if table
+                    .get(to_bytes(&note.relative_path).as_slice())?
+                    .is_some()
+                {
+                    continue;
+                }
+                let serialized_note = serialize_note(note)?;
+                insert(&mut table, &mut due, &serialized_note)?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(&self, notes: Vec<Note>) -> Result<()> {
+        let transaction = self.database.begin_write()?;
+        {
+            let mut table = transaction.open_table(NOTES)?;
+            let mut due = transaction.open_table(DUE)?;
+            for mut note in notes {
+                let stored = match table.get(to_bytes(&note.relative_path).as_slice())? {
+                    Some(json) => parse_note(json.value())?,
+                    None => continue,
+                };
+                if stored.revision != note.revision {
+                    // dropping the transaction discards the notes updated so far
+                    return Err(ConflictError {
+                        relative_path: note.relative_path,
+                        expected: note.revision,
+                        found: stored.revision,
+                    }
+                    .into());
+                }
+                remove_due(&mut due, &stored)?;
+                note.revision += 1;
+                let serialized_note = serialize_note(note)?;
+                insert(&mut table, &mut due, &serialized_note)?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_one(&self, path: &Path) -> Result<Note> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(NOTES)?;
+        let json = table.get(to_bytes(path).as_slice())?.ok_or(NotFoundError {
+            relative_path: path.to_path_buf(),
+        })?;
+        deserialize_note(parse_note(json.value())?)
+    }
+
+    fn get_all(&self) -> Result<Vec<Note>> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(NOTES)?;
+        table
+            .iter()?
+            .map(|entry| {
+                let (_, json) = entry?;
+                deserialize_note(parse_note(json.value())?)
+            })
+            .collect()
+    }
+
+    fn get_all_lenient(&self) -> Result<LoadReport> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(NOTES)?;
+        let mut report = LoadReport::default();
+        for entry in table.iter()? {
+            let (relative_path, json) = entry?;
+            match parse_note(json.value()).and_then(deserialize_note) {
+                Ok(note) => report.notes.push(note),
+                Err(err) => report
+                    .errors
+                    .push((from_bytes(relative_path.value().to_vec()), err)),
+            }
+        }
+        Ok(report)
+    }
+
+    fn get_due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Result<Vec<IndexEntry>> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(NOTES)?;
+        let due = transaction.open_table(DUE)?;
+        let mut entries = vec![];
+        // keys are truncated to microseconds, so check the exact due date as well
+        for key in due.range(..(due_key(before).saturating_add(1), [].as_slice()))? {
+            if entries.len() >= limit {
+                break;
+            }
+            let (key, _) = key?;
+            let (_, relative_path) = key.value();
+            let json = table.get(relative_path)?.ok_or(anyhow!(
+                "Due date of {:?} has no note",
+                from_bytes(relative_path.to_vec())
+            ))?;
+            let serialized_note = parse_note(json.value())?;
+            if serialized_note.next_datetime > *before {
+                continue;
+            }
+            let scheduler = serialized_note.scheduler["type"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            entries.push(IndexEntry {
+                relative_path: serialized_note.relative_path,
+                next_datetime: serialized_note.next_datetime,
+                scheduler,
+            });
+        }
+        Ok(entries)
+    }
+
+    // a record which cannot be read fails `get_due` rather than being left out
+    fn get_broken(&self) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    fn delete(&self, notes: Vec<Note>) -> Result<()> {
+        let transaction = self.database.begin_write()?;
+        {
+            let mut table = transaction.open_table(NOTES)?;
+            let mut due = transaction.open_table(DUE)?;
+            for note in notes {
+                let stored = match table.remove(to_bytes(&note.relative_path).as_slice())? {
+                    Some(json) => parse_note(json.value())?,
+                    None => return Err(anyhow!("{:?} is not tracked", note.relative_path)),
+                };
+                remove_due(&mut due, &stored)?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn rename(&self, renames: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let transaction = self.database.begin_write()?;
+        {
+            let mut table = transaction.open_table(NOTES)?;
+            let mut due = transaction.open_table(DUE)?;
+            let mut destinations = HashSet::new();
+            for (from, to) in renames.iter() {
+                if table.get(to_bytes(to).as_slice())?.is_some() {
+                    return Err(anyhow!("{to:?} is already tracked"));
+                }
+                if !destinations.insert(to) {
+                    return Err(anyhow!("More than one note would be moved to {to:?}"));
+                }
+                let mut serialized_note = match table.remove(to_bytes(from).as_slice())? {
+                    Some(json) => parse_note(json.value())?,
+                    None => return Err(anyhow!("{from:?} is not tracked")),
+                };
+                remove_due(&mut due, &serialized_note)?;
+                serialized_note.relative_path = to.clone();
+                insert(&mut table, &mut due, &serialized_note)?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn insert(
+    table: &mut redb::Table<&[u8], &str>,
+    due: &mut redb::Table<(i64, &[u8]), ()>,
+    serialized_note: &SerializedNote,
+) -> Result<()> {
+    let json = serde_json::to_string(serialized_note)
+        .with_context(|| "Failed to serialize note structure")?;
+    let relative_path = to_bytes(&serialized_note.relative_path);
+    table.insert(relative_path.as_slice(), json.as_str())?;
+    due.insert(
+        (
+            due_key(&serialized_note.next_datetime),
+            relative_path.as_slice(),
+        ),
+        (),
+    )?;
+    Ok(())
+}
+
+fn remove_due(
+    due: &mut redb::Table<(i64, &[u8]), ()>,
+    serialized_note: &SerializedNote,
+) -> Result<()> {
+    let relative_path = to_bytes(&serialized_note.relative_path);
+    due.remove((
+        due_key(&serialized_note.next_datetime),
+        relative_path.as_slice(),
+    ))?;
+    Ok(())
+}
+
+fn due_key(datetime: &chrono::NaiveDateTime) -> i64 {
+    datetime.and_utc().timestamp_micros()
+}
+
+fn parse_note(json: &str) -> Result<SerializedNote> {
+    let serialized_note: SerializedNote =
+        serde_json::from_str(json).with_context(|| "Failed to parse stored note")?;
+    if serialized_note.schema_version > migration::SCHEMA_VERSION {
+        let err = migration::NewerSchemaError {
+            version: serialized_note.schema_version,
+        };
+        return Err(anyhow::Error::new(err).context(format!(
+            "Failed to read {:?}",
+            serialized_note.relative_path
+        )));
+    }
+    Ok(serialized_note)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    const TMPDIR_PATH: &str = "target/test-kv";
+
+    #[test]
+    fn store_notes_in_transactions() {
+        let repo = KeyValueRepository::new(Path::new(TMPDIR_PATH)).unwrap();
+        let now = chrono::Local::now().naive_local();
+        let notes = (0..5)
+            .map(|i| {
+                let next_datetime = now + chrono::Duration::days(2 - i);
+                let scheduler = Box::<crate::schedulers::sm2::SuperMemo2>::default();
+                Note::new(format!("due/{i}"), &next_datetime, scheduler)
+            })
+            .collect();
+        repo.create(notes).unwrap();
+        let due = repo.get_due(&now, usize::MAX).unwrap();
+        let pathes = due
+            .iter()
+            .map(|entry| entry.relative_path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pathes, vec!["due/4", "due/3", "due/2"]);
+        assert_eq!(repo.get_due(&now, 1).unwrap().len(), 1);
+
+        // a conflict rolls back the whole update
+        let mut reviewed = repo.get_one(Path::new("due/4")).unwrap();
+        reviewed.review(5);
+        let mut stale = repo.get_one(Path::new("due/3")).unwrap();
+        stale.revision = 7;
+        let err = repo.update(vec![reviewed, stale]).unwrap_err();
+        assert!(err.downcast_ref::<ConflictError>().is_some());
+        assert_eq!(repo.get_one(Path::new("due/4")).unwrap().revision, 0);
+
+        let mut reviewed = repo.get_one(Path::new("due/4")).unwrap();
+        reviewed.review(5);
+        repo.update(vec![reviewed]).unwrap();
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 2);
+
+        let renames = vec![(PathBuf::from("due/3"), PathBuf::from("moved"))];
+        repo.rename(renames).unwrap();
+        assert_eq!(
+            repo.get_due(&now, 1).unwrap()[0].relative_path,
+            Path::new("moved")
+        );
+        let missing = Note::new_default("missing");
+        let moved = repo.get_one(Path::new("moved")).unwrap();
+        assert!(repo.delete(vec![moved, missing]).is_err());
+        assert_eq!(repo.get_all().unwrap().len(), 5);
+        drop(repo);
+        remove_dir_all(TMPDIR_PATH).unwrap();
+    }
+}
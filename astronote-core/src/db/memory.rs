@@ -0,0 +1,192 @@
+//! Repository kept in memory, for embedding astronote and for tests.
+
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::db::index::{Index, IndexEntry};
+use crate::db::{
+    deserialize_note, serialize_note, ConflictError, LoadReport, NotFoundError, Repository,
+};
+use crate::{Note, SerializedNote};
+
+/// A repository which behaves like [`NoteRepository`](crate::db::ron::NoteRepository)
+/// without touching the filesystem.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    notes: Mutex<BTreeMap<PathBuf, SerializedNote>>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start with the given notes, e.g. read from another repository.
+    pub fn from_serialized(serialized_notes: Vec<SerializedNote>) -> Self {
+        let notes = serialized_notes
+            .into_iter()
+            .map(|serialized_note| (serialized_note.relative_path.clone(), serialized_note))
+            .collect();
+        Self {
+            notes: Mutex::new(notes),
+        }
+    }
+
+    /// All notes as stored, sorted by path.
+    pub fn dump(&self) -> Vec<SerializedNote> {
+        self.lock().values().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<PathBuf, SerializedNote>> {
+        // the map is never left half-updated, so a panic elsewhere doesn't spoil it
+        self.notes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn create(&self, notes: Vec<Note>) -> Result<()> {
+        let mut stored = self.lock();
+        for note in notes {
+            if stored.contains_key(&note.relative_path) {
+                continue;
+            }
+            let serialized_note = serialize_note(note)?;
+            stored.insert(serialized_note.relative_path.clone(), serialized_note);
+        }
+        Ok(())
+    }
+
+    fn update(&self, notes: Vec<Note>) -> Result<()> {
+        let mut stored = self.lock();
+        for mut note in notes {
+            let Some(current) = stored.get(&note.relative_path) else {
+                continue;
+            };
+            if current.revision != note.revision {
+                return Err(ConflictError {
+                    relative_path: note.relative_path,
+                    expected: note.revision,
+                    found: current.revision,
+                }
+                .into());
+            }
+            note.revision += 1;
+            let serialized_note = serialize_note(note)?;
+            stored.insert(serialized_note.relative_path.clone(), serialized_note);
+        }
+        Ok(())
+    }
+
+    fn get_one(&self, path: &Path) -> Result<Note> {
+        let serialized_note = self.lock().get(path).cloned().ok_or(NotFoundError {
+            relative_path: path.to_path_buf(),
+        })?;
+        deserialize_note(serialized_note)
+    }
+
+    fn get_all(&self) -> Result<Vec<Note>> {
+        self.dump().into_iter().map(deserialize_note).collect()
+    }
+
+    fn get_all_lenient(&self) -> Result<LoadReport> {
+        let mut report = LoadReport::default();
+        for serialized_note in self.dump() {
+            let path = PathBuf::from(&serialized_note.relative_path);
+            match deserialize_note(serialized_note) {
+                Ok(note) => report.notes.push(note),
+                Err(err) => report.errors.push((path, err)),
+            }
+        }
+        Ok(report)
+    }
+
+    fn get_due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Result<Vec<IndexEntry>> {
+        let mut index = Index::default();
+        for serialized_note in self.lock().values() {
+            index.insert(serialized_note);
+        }
+        Ok(index.due(before, limit))
+    }
+
+    // notes are kept deserialized, so none of them can be broken
+    fn get_broken(&self) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    fn delete(&self, notes: Vec<Note>) -> Result<()> {
+        let mut stored = self.lock();
+        for note in notes {
+            stored
+                .remove(&note.relative_path)
+                .ok_or(anyhow!("{:?} is not tracked", note.relative_path))?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, renames: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let mut stored = self.lock();
+        // validate every pair before touching anything
+        let mut destinations = HashSet::new();
+        for (from, to) in renames.iter() {
+            if !stored.contains_key(from) {
+                return Err(anyhow!("{from:?} is not tracked"));
+            }
+            if stored.contains_key(to) {
+                return Err(anyhow!("{to:?} is already tracked"));
+            }
+            if !destinations.insert(to) {
+                return Err(anyhow!("More than one note would be moved to {to:?}"));
+            }
+        }
+        for (from, to) in renames.iter() {
+            let mut serialized_note = stored.remove(from).expect("validated above");
+            serialized_note.relative_path = to.clone();
+            stored.insert(to.clone(), serialized_note);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn behave_like_ron_repository() {
+        let repo = MemoryRepository::new();
+        repo.create(vec![Note::new_default("a.md"), Note::new_default("b.md")])
+            .unwrap();
+        // existing notes are kept
+        let mut reviewed = repo.get_one(Path::new("a.md")).unwrap();
+        reviewed.review(5);
+        let stale = repo.get_one(Path::new("a.md")).unwrap();
+        repo.update(vec![reviewed]).unwrap();
+        repo.create(vec![Note::new_default("a.md")]).unwrap();
+        assert_eq!(repo.get_one(Path::new("a.md")).unwrap().revision, 1);
+        // concurrent updates are detected, and missing notes are skipped
+        let err = repo.update(vec![stale]).unwrap_err();
+        assert!(err.downcast_ref::<ConflictError>().is_some());
+        repo.update(vec![Note::new_default("missing.md")]).unwrap();
+        assert!(repo.delete(vec![Note::new_default("missing.md")]).is_err());
+
+        let renames = vec![(PathBuf::from("b.md"), PathBuf::from("c.md"))];
+        repo.rename(renames).unwrap();
+        let renames = vec![(PathBuf::from("a.md"), PathBuf::from("c.md"))];
+        assert!(repo.rename(renames).is_err());
+
+        let due = repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap();
+        assert_eq!(due.first().unwrap().relative_path, Path::new("c.md"));
+        let restored = MemoryRepository::from_serialized(repo.dump());
+        let pathes = restored
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|note| note.relative_path)
+            .collect::<Vec<_>>();
+        assert_eq!(pathes, [Path::new("a.md"), Path::new("c.md")]);
+    }
+}
@@ -1 +1,147 @@
+pub mod front_matter;
+pub mod index;
+pub mod kv;
+pub mod memory;
 pub mod ron;
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{Note, SerializedNote};
+use index::IndexEntry;
+
+/// File which is locked while a database (or the notes of front matter storage) is written.
+pub const LOCK_FILE_NAME: &str = ".astronote.lock";
+
+/// Directory in a database which holds the databases of named collections.
+pub const COLLECTIONS_DIR_NAME: &str = ".collections";
+
+/// Database directory of the collection `name` within the database at `database_root`.
+pub fn collection_path(database_root: &Path, name: &str) -> PathBuf {
+    database_root.join(COLLECTIONS_DIR_NAME).join(name)
+}
+
+/// Storage of notes and their scheduling state.
+///
+/// Paths are relative to the root directory of the notes.
+/// Repositories can be shared between threads, e.g. to load notes in the background.
+pub trait Repository: Send + Sync {
+    /// Store new notes; notes which are already stored are left as they are.
+    fn create(&self, notes: Vec<Note>) -> Result<()>;
+
+    /// Write notes back to the storage.
+    ///
+    /// Each note must carry the revision it was read with. If another process has
+    /// updated the note in the meantime, nothing is written for that note and a
+    /// [`ConflictError`] is returned.
+    fn update(&self, notes: Vec<Note>) -> Result<()>;
+
+    /// Load one note; a [`NotFoundError`] is returned if it is not stored.
+    fn get_one(&self, path: &Path) -> Result<Note>;
+
+    fn get_all(&self) -> Result<Vec<Note>>;
+
+    /// Load all notes, skipping those which cannot be loaded.
+    ///
+    /// Unlike [`Repository::get_all`], a broken note does not fail the whole load;
+    /// it is reported in [`LoadReport::errors`] instead.
+    fn get_all_lenient(&self) -> Result<LoadReport>;
+
+    /// Get index entries of notes due at or before `before`, the oldest first.
+    ///
+    /// Use [`Repository::get_one`] to load the notes themselves.
+    fn get_due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Result<Vec<IndexEntry>>;
+
+    /// Files which are left out of [`Repository::get_due`] because they cannot be read.
+    ///
+    /// Use [`Repository::get_all_lenient`] to find out why.
+    fn get_broken(&self) -> Result<Vec<PathBuf>>;
+
+    fn delete(&self, notes: Vec<Note>) -> Result<()>;
+
+    /// Move notes to new paths, e.g. after their files were moved.
+    ///
+    /// Either all notes are moved or, if any of them fails, none is.
+    fn rename(&self, renames: Vec<(PathBuf, PathBuf)>) -> Result<()>;
+}
+
+/// Result of [`Repository::get_all_lenient`].
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub notes: Vec<Note>,
+    /// files which failed to load, with the reason
+    pub errors: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Returned when a note was modified by another process since it was read.
+#[derive(Debug)]
+pub struct ConflictError {
+    pub relative_path: PathBuf,
+    /// revision the caller read
+    pub expected: u64,
+    /// revision currently stored in the database
+    pub found: u64,
+}
+
+impl fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} was modified by another session (expected revision {}, found {})",
+            self.relative_path.display(),
+            self.expected,
+            self.found
+        )
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+/// Returned when a note is not stored in the repository.
+#[derive(Debug)]
+pub struct NotFoundError {
+    pub relative_path: PathBuf,
+}
+
+impl fmt::Display for NotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not tracked", self.relative_path.display())
+    }
+}
+
+impl std::error::Error for NotFoundError {}
+
+pub(crate) fn serialize_note(note: Note) -> Result<SerializedNote> {
+    note.try_into()
+        .with_context(|| "Failed to serialize note structure")
+}
+
+pub(crate) fn deserialize_note(serialized_note: SerializedNote) -> Result<Note> {
+    serialized_note
+        .try_into()
+        .with_context(|| "Failed to deserialize serialized metadata")
+}
+
+/// Write into a temporary file and then move it into place,
+/// so that readers never see a partially written file.
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let temporary_path = {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".tmp");
+        PathBuf::from(path)
+    };
+    let mut file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temporary_path)
+        .with_context(|| format!("Failed to open or create file: {temporary_path:?}"))?;
+    file.write_all(content)
+        .with_context(|| format!("Failed to write to {temporary_path:?}"))?;
+    fs::rename(&temporary_path, path)
+        .with_context(|| format!("Failed to move {temporary_path:?} into place: {path:?}"))?;
+    Ok(())
+}
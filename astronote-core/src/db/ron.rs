@@ -1,14 +1,66 @@
 use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
 use std::fs::{self, read_dir, DirBuilder, File};
-use std::io::{prelude::*, ErrorKind};
+use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-use crate::{Note, SerializedNote};
+use crate::db::index::{Index, IndexEntry};
+pub use crate::db::LoadReport;
+use crate::db::{
+    deserialize_note, serialize_note, write_atomically, ConflictError, NotFoundError, Repository,
+    COLLECTIONS_DIR_NAME, LOCK_FILE_NAME,
+};
+use crate::{migration, Note, SerializedNote};
+
+mod backup;
+mod doctor;
+mod merge;
+mod sync;
+
+pub use backup::{list_snapshots, prune_snapshots, read_manifest, Manifest};
+pub use doctor::Problem;
+pub use merge::merge_metadata;
+pub use sync::SyncReport;
+
+const METADATA_EXTENSION: &str = "metadata";
+// NOTE: metadata must be < 10 KiB; rather fail to prevent zipbomb
+const METADATA_SIZE_LIMIT: usize = 10 * 1024;
+const INDEX_FILE_NAME: &str = ".astronote.index";
 
 pub struct NoteRepository {
     database_dir: PathBuf,
 }
 
+/// Result of [`NoteRepository::migrate_layout`].
+#[derive(Debug, Default)]
+pub struct LayoutMigration {
+    /// metadata files moved from the old location to the new one
+    pub moved: Vec<(PathBuf, PathBuf)>,
+    pub collisions: Vec<LayoutCollision>,
+    /// metadata files which could not be read or moved; they are left in place
+    pub errors: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Result of [`NoteRepository::migrate_schema`].
+#[derive(Debug, Default)]
+pub struct SchemaMigration {
+    /// metadata files rewritten in the current schema version
+    pub upgraded: Vec<PathBuf>,
+    /// metadata files left as they are because they are not where the metadata of their
+    /// note belongs, e.g. those [`NoteRepository::migrate_layout`] could not move
+    pub misplaced: Vec<PathBuf>,
+    pub errors: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// A metadata file which could not be moved because its new location is taken.
+#[derive(Debug)]
+pub struct LayoutCollision {
+    pub relative_path: PathBuf,
+    pub metadata_path: PathBuf,
+    pub existing_path: PathBuf,
+}
+
 impl NoteRepository {
     pub fn new(database_root: &Path) -> Result<Self> {
         if !database_root.exists() {
@@ -24,46 +76,391 @@ impl NoteRepository {
         })
     }
 
-    pub fn create(&self, notes: Vec<Note>) -> Result<()> {
-        let _result = notes
+    /// Open an existing database, e.g. to sync with it; unlike [`NoteRepository::new`],
+    /// no directory is created.
+    ///
+    /// The directory must be empty or hold a database, so that a mistyped path
+    /// is not taken for a database.
+    pub fn open(database_root: &Path) -> Result<Self> {
+        if !database_root.is_dir() {
+            return Err(anyhow!(
+                "Database directory does not exist: {database_root:?}"
+            ));
+        }
+        let is_empty = read_dir(database_root)
+            .with_context(|| format!("Failed to read entries of {database_root:?}"))?
+            .next()
+            .is_none();
+        let is_database = is_empty
+            || [LOCK_FILE_NAME, INDEX_FILE_NAME]
+                .iter()
+                .any(|name| database_root.join(name).exists())
+            || !list_metadata_files(database_root)?.is_empty();
+        if !is_database {
+            return Err(anyhow!("{database_root:?} is not an astronote database"));
+        }
+        Ok(Self {
+            database_dir: PathBuf::from(database_root),
+        })
+    }
+
+    /// Rebuild the index from metadata files, e.g. after editing the database by hand.
+    pub fn rebuild_index(&self) -> Result<()> {
+        let _lock = self.lock()?;
+        let index = self.build_index()?;
+        index.save(&self.index_path())
+    }
+
+    /// Move metadata files written with the old path mapping to their current location.
+    ///
+    /// Files whose new location is already taken, and files which cannot be read
+    /// (e.g. written by a newer astronote), are left in place and reported.
+    pub fn migrate_layout(&self) -> Result<LayoutMigration> {
+        let _lock = self.lock()?;
+        let mut migration = LayoutMigration::default();
+        for metadata_path in list_metadata_files(&self.database_dir)? {
+            let serialized_note = match read_serialized_metadata(&metadata_path) {
+                Ok(serialized_note) => serialized_note,
+                Err(err) => {
+                    migration.errors.push((metadata_path, err));
+                    continue;
+                }
+            };
+            let expected_path =
+                get_metadata_path_from_path(&serialized_note.relative_path, &self.database_dir);
+            if expected_path == metadata_path {
+                continue;
+            }
+            if expected_path.exists() {
+                migration.collisions.push(LayoutCollision {
+                    relative_path: serialized_note.relative_path,
+                    metadata_path,
+                    existing_path: expected_path,
+                });
+                continue;
+            }
+            let result = fs::rename(&metadata_path, &expected_path)
+                .with_context(|| format!("Failed to move {metadata_path:?} to {expected_path:?}"));
+            match result {
+                Ok(()) => migration.moved.push((metadata_path, expected_path)),
+                Err(err) => migration.errors.push((metadata_path, err)),
+            }
+        }
+        Ok(migration)
+    }
+
+    /// Rewrite metadata files written with an older schema version in the current one.
+    ///
+    /// Each file is rewritten where it is; misplaced files are reported instead.
+    pub fn migrate_schema(&self) -> Result<SchemaMigration> {
+        let _lock = self.lock()?;
+        let mut migration = SchemaMigration::default();
+        for metadata_path in list_metadata_files(&self.database_dir)? {
+            let result = read_metadata_string(&metadata_path).and_then(|ron_string| {
+                if migration::schema_version_of_ron(&ron_string)? >= migration::SCHEMA_VERSION {
+                    return Ok(None);
+                }
+                migration::from_ron(&ron_string).map(Some)
+            });
+            let serialized_note = match result {
+                Ok(Some(serialized_note)) => serialized_note,
+                Ok(None) => continue,
+                Err(err) => {
+                    migration.errors.push((metadata_path, err));
+                    continue;
+                }
+            };
+            // e.g. a legacy file next to the current one must not overwrite it
+            let expected_path =
+                get_metadata_path_from_path(&serialized_note.relative_path, &self.database_dir);
+            if expected_path != metadata_path {
+                migration.misplaced.push(metadata_path);
+                continue;
+            }
+            let result = to_ron_string(&serialized_note)
+                .and_then(|ron| write_atomically(&metadata_path, ron.as_bytes()));
+            match result {
+                Ok(()) => migration.upgraded.push(metadata_path),
+                Err(err) => migration.errors.push((metadata_path, err)),
+            }
+        }
+        Ok(migration)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.database_dir.join(INDEX_FILE_NAME)
+    }
+
+    // Load the index, building it from metadata files if it does not exist yet
+    // or is out of date. Callers must hold the exclusive database lock.
+    fn load_index(&self) -> Result<Index> {
+        if let Some(index) = self.load_fresh_index()? {
+            return Ok(index);
+        }
+        let index = self.build_index()?;
+        index.save(&self.index_path())?;
+        Ok(index)
+    }
+
+    // Metadata files are only checked for changes; they are read when the index
+    // has to be rebuilt, which is done under the exclusive lock as it is written.
+    fn read_index(&self) -> Result<Index> {
+        let index = {
+            let _lock = self.lock_shared()?;
+            self.load_fresh_index()?
+        };
+        match index {
+            Some(index) => Ok(index),
+            None => {
+                let _lock = self.lock()?;
+                self.load_index()
+            }
+        }
+    }
+
+    // Load the index if it is up to date; nothing is written, so the shared lock is enough.
+    fn load_fresh_index(&self) -> Result<Option<Index>> {
+        let index_path = self.index_path();
+        // an index which cannot be read, e.g. one written in an older format, is rebuilt
+        match Index::load(&index_path) {
+            Ok(index) if !self.is_index_stale(&index, &index_path)? => Ok(Some(index)),
+            _ => Ok(None),
+        }
+    }
+
+    // Metadata files may change behind our back (e.g. `git pull`). Adding, removing or
+    // replacing a file touches its directory, so only the directories are checked rather
+    // than every file; a file rewritten in place goes unnoticed until the index is rebuilt.
+    fn is_index_stale(&self, index: &Index, index_path: &Path) -> Result<bool> {
+        let index_modified = fs::metadata(index_path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to get modification time of {index_path:?}"))?;
+        let mut directories = index.directories().peekable();
+        // an index written before directories were recorded
+        if directories.peek().is_none() {
+            return Ok(true);
+        }
+        Ok(directories.any(|directory| {
+            // a directory which is gone has changed too
+            fs::metadata(self.database_dir.join(directory))
+                .and_then(|metadata| metadata.modified())
+                .map_or(true, |modified| modified > index_modified)
+        }))
+    }
+
+    // Broken metadata files are only recorded as such; they are reported by `diagnose`.
+    fn build_index(&self) -> Result<Index> {
+        let results = list_metadata_files(&self.database_dir)?
+            .into_par_iter()
+            .map(|metadata_path| {
+                let result = read_serialized_metadata(&metadata_path);
+                (metadata_path, result)
+            })
+            .collect::<Vec<_>>();
+        let mut index = Index::default();
+        index.insert_directory(Path::new(""));
+        for (metadata_path, result) in results {
+            let relative_path = metadata_path
+                .strip_prefix(&self.database_dir)
+                .unwrap_or(&metadata_path);
+            index.insert_directory(metadata_directory(relative_path));
+            match result {
+                Ok(serialized_note) => index.insert(&serialized_note),
+                Err(_) => index.insert_broken(relative_path.to_path_buf()),
+            }
+        }
+        Ok(index)
+    }
+
+    // Take an exclusive advisory lock on the database.
+    // The lock is released when the returned file is dropped (or the process dies).
+    fn lock(&self) -> Result<File> {
+        let file = self.open_lock_file()?;
+        file.lock()
+            .with_context(|| format!("Failed to lock database: {:?}", self.database_dir))?;
+        Ok(file)
+    }
+
+    fn lock_shared(&self) -> Result<File> {
+        let file = self.open_lock_file()?;
+        file.lock_shared()
+            .with_context(|| format!("Failed to lock database: {:?}", self.database_dir))?;
+        Ok(file)
+    }
+
+    fn open_lock_file(&self) -> Result<File> {
+        let lock_path = self.database_dir.join(LOCK_FILE_NAME);
+        File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file: {lock_path:?}"))
+    }
+}
+
+impl Repository for NoteRepository {
+    fn create(&self, notes: Vec<Note>) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut index = self.load_index()?;
+        let result = notes
             .into_iter()
             // filter out existing metadata
-            .filter(|note| !get_metadata_path_from_note(&note, &self.database_dir).exists())
-            .map(|note| write_metadata(note, &self.database_dir))
-            .collect::<Result<Vec<_>>>()?;
-        return Ok(());
+            .filter(|note| !get_metadata_path_from_note(note, &self.database_dir).exists())
+            .map(|note| {
+                let serialized_note = serialize_note(note)?;
+                write_metadata(&serialized_note, &self.database_dir)?;
+                index.insert(&serialized_note);
+                index.insert_directory(metadata_directory(&serialized_note.relative_path));
+                anyhow::Ok(())
+            })
+            .collect::<Result<Vec<_>>>();
+        // keep the index in sync with whatever has been written so far
+        index.save(&self.index_path())?;
+        result?;
+        Ok(())
     }
 
-    pub fn update(&self, notes: Vec<Note>) -> Result<()> {
-        let _result = notes
+    fn update(&self, notes: Vec<Note>) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut index = self.load_index()?;
+        let result = notes
             .into_iter()
             // filter out not-existing metadata
-            .filter(|note| get_metadata_path_from_note(&note, &self.database_dir).exists())
-            .map(|note| write_metadata(note, &self.database_dir))
-            .collect::<Result<Vec<_>>>()?;
-        return Ok(());
+            .filter(|note| get_metadata_path_from_note(note, &self.database_dir).exists())
+            .map(|mut note| {
+                let metadata_path = get_metadata_path_from_note(&note, &self.database_dir);
+                let stored = read_serialized_metadata(&metadata_path)?;
+                if stored.revision != note.revision {
+                    return Err(ConflictError {
+                        relative_path: note.relative_path,
+                        expected: note.revision,
+                        found: stored.revision,
+                    }
+                    .into());
+                }
+                note.revision += 1;
+                let serialized_note = serialize_note(note)?;
+                write_metadata(&serialized_note, &self.database_dir)?;
+                index.insert(&serialized_note);
+                Ok(())
+            })
+            .collect::<Result<Vec<_>>>();
+        index.save(&self.index_path())?;
+        result?;
+        Ok(())
     }
 
-    pub fn get_one(&self, path: &Path) -> Result<Note> {
-        let path = get_metadata_path_from_path(path, &self.database_dir);
-        read_metadata(&path)
+    fn get_one(&self, path: &Path) -> Result<Note> {
+        let metadata_path = get_metadata_path_from_path(path, &self.database_dir);
+        if !metadata_path.exists() {
+            if get_legacy_metadata_path_from_path(path, &self.database_dir).exists() {
+                return Err(anyhow!(
+                    "Metadata of {path:?} is stored in the old database layout; run `astronote migrate`"
+                ));
+            }
+            return Err(NotFoundError {
+                relative_path: path.to_path_buf(),
+            }
+            .into());
+        }
+        read_metadata(&metadata_path)
     }
 
-    pub fn get_all(&self) -> Result<Vec<Note>> {
-        read_metadata_from_directory(&self.database_dir)
+    fn get_all(&self) -> Result<Vec<Note>> {
+        read_metadata_from_directory(&self.database_dir)?
+            .into_par_iter()
+            .map(deserialize_note)
+            .collect()
     }
 
-    pub fn delete(&self, notes: Vec<Note>) -> Result<()> {
-        let _result = notes
+    fn get_all_lenient(&self) -> Result<LoadReport> {
+        let results = list_metadata_files(&self.database_dir)?
+            .into_par_iter()
+            .map(|metadata_path| {
+                let result = read_metadata(&metadata_path);
+                (metadata_path, result)
+            })
+            .collect::<Vec<_>>();
+        let mut report = LoadReport::default();
+        for (metadata_path, result) in results {
+            match result {
+                Ok(note) => report.notes.push(note),
+                Err(err) => report.errors.push((metadata_path, err)),
+            }
+        }
+        Ok(report)
+    }
+
+    fn get_due(&self, before: &chrono::NaiveDateTime, limit: usize) -> Result<Vec<IndexEntry>> {
+        Ok(self.read_index()?.due(before, limit))
+    }
+
+    fn get_broken(&self) -> Result<Vec<PathBuf>> {
+        let index = self.read_index()?;
+        let broken = index
+            .broken()
+            .map(|metadata_path| self.database_dir.join(metadata_path));
+        Ok(broken.collect())
+    }
+
+    fn delete(&self, notes: Vec<Note>) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut index = self.load_index()?;
+        let result = notes
             .into_iter()
-            .map(|note| delete_metadata(note, &self.database_dir))
-            .collect::<Result<Vec<_>>>()?;
-        return Ok(());
+            .map(|note| {
+                index.remove(&note.relative_path);
+                delete_metadata(note, &self.database_dir)
+            })
+            .collect::<Result<Vec<_>>>();
+        index.save(&self.index_path())?;
+        result?;
+        Ok(())
+    }
+
+    fn rename(&self, renames: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let _lock = self.lock()?;
+        // validate every pair before touching anything
+        let mut destinations = HashSet::new();
+        for (from, to) in renames.iter() {
+            if !get_metadata_path_from_path(from, &self.database_dir).exists() {
+                return Err(anyhow!("{from:?} is not tracked"));
+            }
+            if get_metadata_path_from_path(to, &self.database_dir).exists() {
+                return Err(anyhow!("{to:?} is already tracked"));
+            }
+            if !destinations.insert(to) {
+                return Err(anyhow!("More than one note would be moved to {to:?}"));
+            }
+        }
+        let mut index = self.load_index()?;
+        let mut done: Vec<(&PathBuf, &PathBuf)> = vec![];
+        for (from, to) in renames.iter() {
+            match rename_metadata(from, to, &self.database_dir) {
+                Ok(serialized_note) => {
+                    index.remove(from);
+                    index.insert(&serialized_note);
+                    index.insert_directory(metadata_directory(to));
+                    done.push((from, to));
+                }
+                Err(err) => {
+                    // move back what has been moved so far; the index is left untouched
+                    for (from, to) in done.into_iter().rev() {
+                        rename_metadata(to, from, &self.database_dir).with_context(|| {
+                            format!("Failed to roll back moving {from:?} to {to:?}")
+                        })?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        index.save(&self.index_path())
     }
 }
 
-fn write_metadata(note: Note, database_root: &Path) -> Result<()> {
-    let metadata_path = get_metadata_path_from_note(&note, database_root);
+fn write_metadata(serialized_note: &SerializedNote, database_root: &Path) -> Result<()> {
+    let metadata_path = get_metadata_path_from_path(&serialized_note.relative_path, database_root);
     // create directory to store metadata under `database_root` if not exists
     let parent_dir_path = metadata_path
         .parent()
@@ -79,21 +476,22 @@ fn write_metadata(note: Note, database_root: &Path) -> Result<()> {
             .with_context(|| format!("Failed to create metadata directory: {parent_dir_path:?}"))?;
     }
     // convert note into ron string
-    let serialized_note: SerializedNote = note
-        .try_into()
-        .with_context(|| "Failed to serialize note structure")?;
-    let ron = ron::ser::to_string_pretty(&serialized_note, ron::ser::PrettyConfig::default())
+    let ron = to_ron_string(serialized_note)?;
+    write_atomically(&metadata_path, ron.as_bytes())?;
+    // the note is (re)created; don't let sync delete it again
+    sync::remove_tombstone(&serialized_note.relative_path, database_root)
+}
+
+// Metadata is formatted the same on every platform so that it can be kept in git.
+// Fields are written in declaration order and scheduler fields in alphabetical order.
+fn to_ron_string(serialized_note: &SerializedNote) -> Result<String> {
+    let config = ron::ser::PrettyConfig::new()
+        .new_line("\n".to_string())
+        .indentor("    ".to_string());
+    let mut ron = ron::ser::to_string_pretty(serialized_note, config)
         .with_context(|| "Failed to generate RON string from serialized object")?;
-    // write into file
-    let mut file = File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(metadata_path)
-        .with_context(|| "Failed to open or create metadata file: {metadata_path:?}")?;
-    file.write_all(ron.as_bytes())
-        .with_context(|| "Failed to write metadata to {metadata_path:?}")?;
-    Ok(())
+    ron.push('\n');
+    Ok(ron)
 }
 
 fn delete_metadata(note: Note, database_root: &Path) -> Result<()> {
@@ -104,73 +502,135 @@ fn delete_metadata(note: Note, database_root: &Path) -> Result<()> {
             metadata_path
         ));
     }
-    fs::remove_file(&metadata_path)
-        .with_context(|| format!("Failed to remove metadata file: {:?}", metadata_path))?;
-    return Ok(());
+    remove_metadata_file(&metadata_path, &note.relative_path, database_root)
+}
+
+// Remove the metadata file of a note and leave a tombstone behind,
+// so that a sync removes the note from the other database too.
+fn remove_metadata_file(
+    metadata_path: &Path,
+    relative_path: &Path,
+    database_root: &Path,
+) -> Result<()> {
+    fs::remove_file(metadata_path)
+        .with_context(|| format!("Failed to remove metadata file: {metadata_path:?}"))?;
+    sync::write_tombstone(relative_path, database_root)
+}
+
+fn rename_metadata(from: &Path, to: &Path, database_root: &Path) -> Result<SerializedNote> {
+    let from_path = get_metadata_path_from_path(from, database_root);
+    let to_path = get_metadata_path_from_path(to, database_root);
+    if !from_path.exists() {
+        return Err(anyhow!(
+            "Failed to find metadata file to move: {from_path:?}"
+        ));
+    }
+    if to_path.exists() {
+        return Err(anyhow!("{to:?} is already tracked: {to_path:?}"));
+    }
+    let mut serialized_note = read_serialized_metadata(&from_path)?;
+    serialized_note.relative_path = to.to_path_buf();
+    write_metadata(&serialized_note, database_root)?;
+    remove_metadata_file(&from_path, from, database_root)?;
+    Ok(serialized_note)
 }
 
 // 1. directory recursively
-// 2. read each metadata file
+// 2. read each metadata file (other files such as lock files are skipped)
 // 3. and then convert the content into Note
-fn read_metadata_from_directory(dir: &Path) -> Result<Vec<Note>> {
+fn read_metadata_from_directory(dir: &Path) -> Result<Vec<SerializedNote>> {
+    list_metadata_files(dir)?
+        .into_par_iter()
+        .map(|path| {
+            read_serialized_metadata(&path)
+                .with_context(|| format!("Failed to read note metadata from {path:?}"))
+        })
+        .collect()
+}
+
+fn list_metadata_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    list_files(dir, is_metadata_file)
+}
+
+fn list_files(dir: &Path, is_target: fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
     let pathes: Vec<PathBuf> = read_dir(dir)
         .with_context(|| format!("Failed to read entries of {dir:?}"))?
-        .into_iter()
         .map(|entry| Ok(entry?.path()))
         .collect::<Result<Vec<PathBuf>>>()
         .with_context(|| {
             format!("There's some sort of intermittent IO error during reading directory: {dir:?}")
         })?;
+    // subdirectories are traversed in parallel; collections are databases of their own
     let result = pathes
-        .into_iter()
+        .into_par_iter()
+        .filter(|path| !path.ends_with(COLLECTIONS_DIR_NAME))
+        .filter(|path| path.is_dir() || is_target(path))
         .map(|path| {
             let result = if path.is_dir() {
-                read_metadata_from_directory(&path)?
+                list_files(&path, is_target)?
             } else {
-                let note = read_metadata(&path)
-                    .with_context(|| format!("Failed to read note metadata from {path:?}"))?;
-                vec![note]
+                vec![path]
             };
             anyhow::Ok(result)
         })
-        .try_fold(vec![], |mut acc, result_note_vec| {
-            let note_vec = result_note_vec?;
-            acc.extend(note_vec);
-            anyhow::Ok(acc)
-        })?;
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
     Ok(result)
 }
 
 fn read_metadata(filepath: &Path) -> Result<Note> {
-    let ron_string: String = {
-        let file = File::open(&filepath).with_context(|| format!("Failed to open {filepath:?}"))?;
-        let mut buf = String::new();
-        // NOTE: metadata must be < 10 KiB; rather fail to prevent zipbomb
-        let _size = LimitReader::new(file, 10 * 1024)
-            .read_to_string(&mut buf)
-            .with_context(|| format!("Failed to read file content of {filepath:?}"))?;
-        anyhow::Ok(buf)
-    }?;
-    let serialized_note: SerializedNote = ron::from_str(&ron_string).with_context(|| {
+    let serialized_note = read_serialized_metadata(filepath)?;
+    deserialize_note(serialized_note)
+}
+
+fn read_serialized_metadata(filepath: &Path) -> Result<SerializedNote> {
+    let ron_string = read_metadata_string(filepath)?;
+    let serialized_note = migration::from_ron(&ron_string).with_context(|| {
         format!("Failed to generate serialized metadata object from RON of {filepath:?}")
     })?;
-    let note: Note = serialized_note
-        .try_into()
-        .with_context(|| "Failed to deserialize serialized metadata")?;
-    Ok(note)
+    Ok(serialized_note)
+}
+
+fn read_metadata_string(filepath: &Path) -> Result<String> {
+    let file = File::open(filepath).with_context(|| format!("Failed to open {filepath:?}"))?;
+    let mut buf = String::new();
+    let _size = LimitReader::new(file, METADATA_SIZE_LIMIT)
+        .read_to_string(&mut buf)
+        .with_context(|| format!("Failed to read file content of {filepath:?}"))?;
+    Ok(buf)
+}
+
+fn is_metadata_file(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|ext| ext == METADATA_EXTENSION)
+}
+
+// Directory holding the metadata file of `path`, relative to the database directory.
+fn metadata_directory(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
 }
 
 fn get_metadata_path_from_note(note: &Note, database_root: &Path) -> PathBuf {
-    let note_path = PathBuf::from(&note.relative_path);
-    let path = get_metadata_path_from_path(&note_path, database_root);
-    path
+    get_metadata_path_from_path(&note.relative_path, database_root)
 }
 
+// The suffix is appended rather than replacing the extension,
+// so that `rust.md` and `rust.txt` map to different metadata files.
 fn get_metadata_path_from_path(path: &Path, database_root: &Path) -> PathBuf {
-    let mut path = [PathBuf::from(database_root), PathBuf::from(path)]
-        .iter()
-        .collect::<PathBuf>();
-    path.set_extension("metadata");
+    let mut path = database_root.join(path).into_os_string();
+    path.push(".");
+    path.push(METADATA_EXTENSION);
+    PathBuf::from(path)
+}
+
+// Metadata path used before the suffix was appended (`rust.md` -> `rust.metadata`).
+fn get_legacy_metadata_path_from_path(path: &Path, database_root: &Path) -> PathBuf {
+    let mut path = database_root.join(path);
+    path.set_extension(METADATA_EXTENSION);
     path
 }
 
@@ -194,7 +654,7 @@ where
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if buf.len() > self.limit {
-            return Err(std::io::Error::new(ErrorKind::Other, "too many bytes"));
+            return Err(std::io::Error::other("too many bytes"));
         }
         let size = self.reader.read(buf)?;
         self.limit -= size;
@@ -205,6 +665,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::schedulers::sm2::SuperMemo2;
     use rand::{distributions::Alphanumeric, Rng};
     use std::fs::remove_dir_all;
 
@@ -220,7 +681,7 @@ mod tests {
         let path = format!("test/test/{}", randstr);
         let note = Note::new_default(&path);
 
-        let database_root = PathBuf::from(TMPDIR_PATH);
+        let database_root = PathBuf::from(TMPDIR_PATH).join("create_metadata");
         let repo = NoteRepository::new(&database_root).unwrap();
         repo.create(vec![note]).unwrap();
 
@@ -229,4 +690,275 @@ mod tests {
         println!("note: {:?}", note_from_file);
         remove_dir_all(&database_root).unwrap();
     }
+
+    #[test]
+    fn detect_conflicting_update() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("detect_conflicting_update");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![Note::new_default("conflict")]).unwrap();
+
+        // two sessions read the same note
+        let path = Path::new("conflict");
+        let first = repo.get_one(path).unwrap();
+        let second = repo.get_one(path).unwrap();
+
+        repo.update(vec![first]).unwrap();
+        let error = repo.update(vec![second]).unwrap_err();
+        assert!(error.downcast_ref::<ConflictError>().is_some());
+        assert_eq!(repo.get_one(path).unwrap().revision, 1);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn get_due_notes_from_index() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("get_due_notes_from_index");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let now = chrono::Local::now().naive_local();
+        let notes = (0..5)
+            .map(|i| {
+                let next_datetime = now + chrono::Duration::days(i - 2);
+                Note::new(
+                    format!("due/{i}"),
+                    &next_datetime,
+                    Box::<SuperMemo2>::default(),
+                )
+            })
+            .collect();
+        repo.create(notes).unwrap();
+
+        let due = repo.get_due(&now, usize::MAX).unwrap();
+        let paths = due
+            .iter()
+            .map(|e| e.relative_path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["due/0", "due/1", "due/2"]);
+        assert_eq!(repo.get_due(&now, 1).unwrap().len(), 1);
+        // the index written along with the notes is up to date
+        assert!(repo.load_fresh_index().unwrap().is_some());
+
+        // the index follows deletion, and is rebuilt when missing
+        repo.delete(vec![repo.get_one(Path::new("due/0")).unwrap()])
+            .unwrap();
+        fs::remove_file(database_root.join(INDEX_FILE_NAME)).unwrap();
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 2);
+
+        // ... and when metadata files are replaced by someone else;
+        // changes within the same tick of the clock go unnoticed, so move time forward
+        let touch = |directory: &Path| {
+            let index_modified = fs::metadata(database_root.join(INDEX_FILE_NAME))
+                .and_then(|metadata| metadata.modified())
+                .unwrap();
+            File::open(database_root.join(directory))
+                .and_then(|dir| {
+                    dir.set_modified(index_modified + std::time::Duration::from_secs(1))
+                })
+                .unwrap();
+        };
+        let mut note = repo.get_one(Path::new("due/3")).unwrap();
+        note.next_datetime = now - chrono::Duration::days(1);
+        let serialized_note = serialize_note(note).unwrap();
+        write_metadata(&serialized_note, &database_root).unwrap();
+        touch(Path::new("due"));
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 3);
+        // ... or added in a new directory
+        let note = Note::new("new/dir/a", &now, Box::<SuperMemo2>::default());
+        write_metadata(&serialize_note(note).unwrap(), &database_root).unwrap();
+        touch(Path::new(""));
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 4);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn store_notes_with_same_stem() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("store_notes_with_same_stem");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let notes = [
+            "notes/rust.md",
+            "notes/rust.txt",
+            "notes/rust",
+            "rust.metadata",
+        ]
+        .into_iter()
+        .map(Note::new_default)
+        .collect();
+        repo.create(notes).unwrap();
+        assert_eq!(repo.get_all().unwrap().len(), 4);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_layout() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("migrate_legacy_layout");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![Note::new_default("rust.md")]).unwrap();
+        let current_path = database_root.join("rust.md.metadata");
+        let legacy_path = database_root.join("rust.metadata");
+        fs::rename(&current_path, &legacy_path).unwrap();
+        assert!(repo.get_one(Path::new("rust.md")).is_err());
+        // a broken file doesn't stop the others from being migrated
+        let broken_path = database_root.join("broken.metadata");
+        fs::write(&broken_path, "(").unwrap();
+
+        let migration = repo.migrate_layout().unwrap();
+        assert_eq!(migration.moved, vec![(legacy_path, current_path)]);
+        assert!(migration.collisions.is_empty());
+        assert_eq!(migration.errors.len(), 1);
+        assert_eq!(migration.errors[0].0, broken_path);
+        assert!(repo.get_one(Path::new("rust.md")).is_ok());
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn store_non_utf8_path() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("store_non_utf8_path");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        // "café.md" in Latin-1
+        let latin1 = crate::encoded_path::from_bytes(b"caf\xe9.md".to_vec());
+        repo.create(vec![Note::new_default(&latin1)]).unwrap();
+
+        let note = repo.get_one(&latin1).unwrap();
+        assert_eq!(note.relative_path, latin1);
+        let due = repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap();
+        assert_eq!(due[0].relative_path, latin1);
+        repo.delete(vec![note]).unwrap();
+        assert!(repo.get_all().unwrap().is_empty());
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn rename_note() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("rename_note");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![
+            Note::new_default("old/a.md"),
+            Note::new_default("b.md"),
+        ])
+        .unwrap();
+
+        let rename =
+            |from: &str, to: &str| repo.rename(vec![(PathBuf::from(from), PathBuf::from(to))]);
+        rename("old/a.md", "new/a.md").unwrap();
+        assert!(rename("new/a.md", "b.md").is_err());
+        let error = repo.get_one(Path::new("old/a.md")).unwrap_err();
+        assert!(error.downcast_ref::<NotFoundError>().is_some());
+        let note = repo.get_one(Path::new("new/a.md")).unwrap();
+        assert_eq!(note.relative_path, Path::new("new/a.md"));
+        let now = chrono::Local::now().naive_local();
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 2);
+
+        // nothing is moved if any of the notes cannot be moved
+        let renames = vec![
+            (PathBuf::from("new/a.md"), PathBuf::from("c.md")),
+            (PathBuf::from("missing.md"), PathBuf::from("d.md")),
+        ];
+        assert!(repo.rename(renames).is_err());
+        assert!(repo.get_one(Path::new("new/a.md")).is_ok());
+        assert!(repo.get_one(Path::new("c.md")).is_err());
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn keep_collections_apart() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("keep_collections_apart");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let wiki =
+            NoteRepository::new(&crate::db::collection_path(&database_root, "wiki")).unwrap();
+        repo.create(vec![Note::new_default("a.md")]).unwrap();
+        wiki.create(vec![Note::new_default("a.md"), Note::new_default("b.md")])
+            .unwrap();
+
+        assert_eq!(repo.get_all().unwrap().len(), 1);
+        assert_eq!(
+            repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap().len(),
+            1
+        );
+        assert_eq!(wiki.get_all().unwrap().len(), 2);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn load_leniently() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("load_leniently");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![Note::new_default("a.md"), Note::new_default("b.md")])
+            .unwrap();
+        let broken_path = database_root.join("broken.md.metadata");
+        fs::write(&broken_path, "(relative_path: ").unwrap();
+        fs::write(database_root.join("a.md.metadata~"), "backup").unwrap();
+        assert!(repo.get_all().is_err());
+
+        let report = repo.get_all_lenient().unwrap();
+        assert_eq!(report.notes.len(), 2);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, broken_path);
+        // broken files do not prevent building the index, nor make it stale
+        let now = chrono::Local::now().naive_local();
+        assert_eq!(repo.get_due(&now, usize::MAX).unwrap().len(), 2);
+        assert!(repo.load_fresh_index().unwrap().is_some());
+        assert_eq!(repo.get_broken().unwrap(), vec![broken_path]);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn migrate_unversioned_metadata() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("migrate_unversioned_metadata");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let metadata_path = database_root.join("a.md.metadata");
+        let ron_string = r#"(
+            relative_path: "a.md",
+            next_datetime: "2023-10-01T12:00:00",
+            scheduler: { "counter": 0, "easiness_factor": 2.5, "interval": 0, "type": "SuperMemo2" },
+        )"#;
+        fs::write(&metadata_path, ron_string).unwrap();
+
+        let migration = repo.migrate_schema().unwrap();
+        assert_eq!(migration.upgraded, vec![metadata_path.clone()]);
+        let ron_string = fs::read_to_string(&metadata_path).unwrap();
+        let version = migration::schema_version_of_ron(&ron_string).unwrap();
+        assert_eq!(version, migration::SCHEMA_VERSION);
+        assert!(repo.migrate_schema().unwrap().upgraded.is_empty());
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn keep_misplaced_metadata_on_upgrade() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("keep_misplaced_metadata_on_upgrade");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![Note::new_default("rust.md")]).unwrap();
+        let current_path = database_root.join("rust.md.metadata");
+        let current = fs::read_to_string(&current_path).unwrap();
+        // left behind by `migrate_layout` as its new location is taken
+        let legacy_path = database_root.join("rust.metadata");
+        let ron_string = r#"(
+            relative_path: "rust.md",
+            next_datetime: "2023-10-01T12:00:00",
+            scheduler: { "counter": 0, "easiness_factor": 2.5, "interval": 0, "type": "SuperMemo2" },
+        )"#;
+        fs::write(&legacy_path, ron_string).unwrap();
+        assert_eq!(repo.migrate_layout().unwrap().collisions.len(), 1);
+
+        let migration = repo.migrate_schema().unwrap();
+        assert!(migration.upgraded.is_empty());
+        assert_eq!(migration.misplaced, vec![legacy_path.clone()]);
+        assert_eq!(fs::read_to_string(&current_path).unwrap(), current);
+        assert_eq!(fs::read_to_string(&legacy_path).unwrap(), ron_string);
+        remove_dir_all(&database_root).unwrap();
+    }
+
+    #[test]
+    fn open_existing_database() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("open_existing_database");
+        assert!(NoteRepository::open(&database_root).is_err());
+        assert!(!database_root.exists());
+
+        fs::create_dir_all(&database_root).unwrap();
+        assert!(NoteRepository::open(&database_root).is_ok());
+        fs::write(database_root.join("notes.txt"), "not a database").unwrap();
+        assert!(NoteRepository::open(&database_root).is_err());
+        let repo = NoteRepository::new(&database_root).unwrap();
+        repo.create(vec![Note::new_default("a.md")]).unwrap();
+        assert!(NoteRepository::open(&database_root).is_ok());
+        remove_dir_all(&database_root).unwrap();
+    }
 }
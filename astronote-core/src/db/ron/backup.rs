@@ -0,0 +1,311 @@
+//! Snapshots of a RON database as zip archives.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::sync::is_tombstone_file;
+use super::*;
+
+const MANIFEST_NAME: &str = "manifest.ron";
+// zip names files in UTF-8; other files are stored under this directory, numbered
+const ENCODED_NAME_DIR: &str = "encoded";
+const SNAPSHOT_PREFIX: &str = "astronote-";
+const SNAPSHOT_EXTENSION: &str = "zip";
+
+/// Description of a snapshot, stored in the archive next to the metadata files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub created_at: chrono::NaiveDateTime,
+    pub note_count: usize,
+    pub schema_version: u32,
+    /// files stored under another name as their paths are not valid UTF-8
+    #[serde(default)]
+    pub encoded_names: Vec<EncodedName>,
+}
+
+/// A file stored in a snapshot under a name other than its path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncodedName {
+    /// name of the entry in the archive
+    pub name: String,
+    /// path of the file, relative to the database directory
+    #[serde(with = "crate::encoded_path")]
+    pub path: PathBuf,
+}
+
+impl NoteRepository {
+    /// Write a snapshot of the database into `backup_dir` and return its path.
+    ///
+    /// The index and the lock file are left out; the index is rebuilt on restore.
+    pub fn backup(&self, backup_dir: &Path) -> Result<PathBuf> {
+        let _lock = self.lock_shared()?;
+        fs::create_dir_all(backup_dir)
+            .with_context(|| format!("Failed to create backup directory: {backup_dir:?}"))?;
+        let created_at = chrono::Local::now().naive_local();
+        let snapshot_path = backup_dir.join(format!(
+            "{SNAPSHOT_PREFIX}{}.{SNAPSHOT_EXTENSION}",
+            created_at.format("%Y%m%d-%H%M%S-%3f")
+        ));
+        let files = list_files(&self.database_dir, |path| {
+            is_metadata_file(path) || is_tombstone_file(path)
+        })?;
+        let mut entries = vec![];
+        let mut encoded_names = vec![];
+        for path in files.iter() {
+            let relative_path = path.strip_prefix(&self.database_dir)?;
+            let components = relative_path
+                .iter()
+                .map(|component| component.to_str())
+                .collect::<Option<Vec<_>>>();
+            let name = match components {
+                Some(components) => components.join("/"),
+                None => {
+                    let name = format!("{ENCODED_NAME_DIR}/{}", encoded_names.len());
+                    encoded_names.push(EncodedName {
+                        name: name.clone(),
+                        path: relative_path.to_path_buf(),
+                    });
+                    name
+                }
+            };
+            entries.push((name, path));
+        }
+        let manifest = Manifest {
+            created_at,
+            note_count: files.iter().filter(|path| is_metadata_file(path)).count(),
+            schema_version: migration::SCHEMA_VERSION,
+            encoded_names,
+        };
+
+        // write next to the snapshot and move it into place once complete
+        let mut temporary_path = snapshot_path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let file = File::create(&temporary_path)
+            .with_context(|| format!("Failed to create {temporary_path:?}"))?;
+        let mut archive = zip::ZipWriter::new(file);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let manifest_ron =
+            ron::ser::to_string(&manifest).with_context(|| "Failed to serialize manifest")?;
+        archive.start_file(MANIFEST_NAME, options)?;
+        archive.write_all(manifest_ron.as_bytes())?;
+        for (name, path) in entries {
+            let content = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+            archive.start_file(name, options)?;
+            archive.write_all(&content)?;
+        }
+        archive
+            .finish()
+            .with_context(|| format!("Failed to write {temporary_path:?}"))?;
+        fs::rename(&temporary_path, &snapshot_path)
+            .with_context(|| format!("Failed to move snapshot into place: {snapshot_path:?}"))?;
+        Ok(snapshot_path)
+    }
+
+    /// Replace the contents of the database with a snapshot.
+    ///
+    /// The whole snapshot is checked before anything in the database is touched.
+    pub fn restore(&self, snapshot_path: &Path) -> Result<Manifest> {
+        let file = File::open(snapshot_path)
+            .with_context(|| format!("Failed to open {snapshot_path:?}"))?;
+        let mut archive = zip::ZipArchive::new(file)
+            .with_context(|| format!("{snapshot_path:?} is not a snapshot"))?;
+        let manifest = read_manifest_from_archive(&mut archive)?;
+        if manifest.schema_version > migration::SCHEMA_VERSION {
+            return Err(anyhow!(
+                "{snapshot_path:?} was taken by a newer version of astronote; please upgrade astronote"
+            ));
+        }
+        let mut files = vec![];
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if entry.is_dir() || entry.name() == MANIFEST_NAME {
+                continue;
+            }
+            let encoded_name = manifest
+                .encoded_names
+                .iter()
+                .find(|encoded_name| encoded_name.name == entry.name());
+            let relative_path = match encoded_name {
+                // like `enclosed_name`, only paths within the database are accepted
+                Some(encoded_name) => Some(encoded_name.path.as_path()).filter(|path| {
+                    path.components()
+                        .all(|component| matches!(component, std::path::Component::Normal(_)))
+                }),
+                None => entry.enclosed_name(),
+            }
+            .ok_or(anyhow!("Invalid file name in snapshot: {}", entry.name()))?
+            .to_path_buf();
+            // don't trust the size recorded in the archive
+            let mut content = vec![];
+            (&mut entry)
+                .take(METADATA_SIZE_LIMIT as u64 + 1)
+                .read_to_end(&mut content)
+                .with_context(|| format!("Failed to read {relative_path:?} from snapshot"))?;
+            if content.len() > METADATA_SIZE_LIMIT {
+                return Err(anyhow!("{relative_path:?} in snapshot is too large"));
+            }
+            files.push((relative_path, content));
+        }
+        let note_count = files
+            .iter()
+            .filter(|(path, _)| {
+                path.extension()
+                    .is_some_and(|ext| ext == METADATA_EXTENSION)
+            })
+            .count();
+        if note_count != manifest.note_count {
+            return Err(anyhow!(
+                "{snapshot_path:?} is incomplete: {note_count} of {} notes found",
+                manifest.note_count
+            ));
+        }
+
+        let _lock = self.lock()?;
+        let current_files = list_files(&self.database_dir, |path| {
+            is_metadata_file(path) || is_tombstone_file(path)
+        })?;
+        for path in current_files {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {path:?}"))?;
+        }
+        for (relative_path, content) in files {
+            let path = self.database_dir.join(relative_path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+            }
+            write_atomically(&path, &content)?;
+        }
+        self.build_index()?.save(&self.index_path())?;
+        Ok(manifest)
+    }
+}
+
+/// Snapshots in `backup_dir`, oldest first.
+pub fn list_snapshots(backup_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !backup_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut snapshots = fs::read_dir(backup_dir)
+        .with_context(|| format!("Failed to read entries of {backup_dir:?}"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    snapshots.retain(|path| {
+        let is_snapshot_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(SNAPSHOT_PREFIX));
+        is_snapshot_name
+            && path
+                .extension()
+                .is_some_and(|ext| ext == SNAPSHOT_EXTENSION)
+    });
+    // names contain the creation time
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Remove all but the newest `keep` snapshots in `backup_dir` and return the removed ones.
+pub fn prune_snapshots(backup_dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let snapshots = list_snapshots(backup_dir)?;
+    let count = snapshots.len().saturating_sub(keep);
+    let removed = snapshots.into_iter().take(count).collect::<Vec<_>>();
+    for path in removed.iter() {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {path:?}"))?;
+    }
+    Ok(removed)
+}
+
+pub fn read_manifest(snapshot_path: &Path) -> Result<Manifest> {
+    let file =
+        File::open(snapshot_path).with_context(|| format!("Failed to open {snapshot_path:?}"))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("{snapshot_path:?} is not a snapshot"))?;
+    read_manifest_from_archive(&mut archive)
+}
+
+fn read_manifest_from_archive(archive: &mut zip::ZipArchive<File>) -> Result<Manifest> {
+    let mut entry = archive
+        .by_name(MANIFEST_NAME)
+        .with_context(|| "Snapshot has no manifest")?;
+    let mut manifest_ron = String::new();
+    (&mut entry)
+        .take(METADATA_SIZE_LIMIT as u64)
+        .read_to_string(&mut manifest_ron)
+        .with_context(|| "Failed to read manifest")?;
+    ron::from_str(&manifest_ron).with_context(|| "Failed to parse manifest")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    const TMPDIR_PATH: &str = "target/test-backup";
+
+    #[test]
+    fn backup_and_restore() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("database");
+        let backup_dir = PathBuf::from(TMPDIR_PATH).join("backups");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let notes = ["a.md", "sub/b.md"]
+            .into_iter()
+            .map(Note::new_default)
+            .collect();
+        repo.create(notes).unwrap();
+
+        let snapshot = repo.backup(&backup_dir).unwrap();
+        let manifest = read_manifest(&snapshot).unwrap();
+        assert_eq!(manifest.note_count, 2);
+        assert_eq!(manifest.schema_version, migration::SCHEMA_VERSION);
+
+        // a bad bulk operation
+        repo.delete(repo.get_all().unwrap()).unwrap();
+        repo.create(vec![Note::new_default("c.md")]).unwrap();
+        repo.restore(&snapshot).unwrap();
+        let mut pathes = repo
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|note| note.relative_path)
+            .collect::<Vec<_>>();
+        pathes.sort();
+        assert_eq!(pathes, [Path::new("a.md"), Path::new("sub/b.md")]);
+        assert_eq!(
+            repo.get_due(&chrono::NaiveDateTime::MAX, 10).unwrap().len(),
+            2
+        );
+
+        repo.backup(&backup_dir).unwrap();
+        assert_eq!(list_snapshots(&backup_dir).unwrap().len(), 2);
+        assert_eq!(prune_snapshots(&backup_dir, 1).unwrap(), vec![snapshot]);
+        remove_dir_all(&database_root).unwrap();
+        remove_dir_all(&backup_dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn backup_non_utf8_path() {
+        let database_root = PathBuf::from(TMPDIR_PATH).join("non_utf8_database");
+        let backup_dir = PathBuf::from(TMPDIR_PATH).join("non_utf8_backups");
+        let repo = NoteRepository::new(&database_root).unwrap();
+        // "café.md" in Latin-1
+        let latin1 = crate::encoded_path::from_bytes(b"caf\xe9.md".to_vec());
+        repo.create(vec![Note::new_default(&latin1), Note::new_default("a.md")])
+            .unwrap();
+
+        let snapshot = repo.backup(&backup_dir).unwrap();
+        let manifest = read_manifest(&snapshot).unwrap();
+        assert_eq!(manifest.note_count, 2);
+        assert_eq!(manifest.encoded_names.len(), 1);
+        repo.delete(repo.get_all().unwrap()).unwrap();
+        repo.restore(&snapshot).unwrap();
+        assert_eq!(repo.get_one(&latin1).unwrap().relative_path, latin1);
+        assert!(repo.get_one(Path::new("a.md")).is_ok());
+        remove_dir_all(&database_root).unwrap();
+        remove_dir_all(&backup_dir).unwrap();
+    }
+}
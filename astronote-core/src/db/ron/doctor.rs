@@ -0,0 +1,247 @@
+//! Consistency checks of a RON database.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::merge::merge_notes;
+use super::*;
+
+/// A problem found by [`NoteRepository::diagnose`].
+#[derive(Debug)]
+pub enum Problem {
+    /// The file of the note does not exist under `root`.
+    Orphan {
+        metadata_path: PathBuf,
+        relative_path: PathBuf,
+    },
+    /// The metadata file cannot be read or deserialized.
+    Corrupt {
+        metadata_path: PathBuf,
+        error: String,
+    },
+    /// The note was written by a newer version of astronote.
+    /// It is not broken and is left intact for that version.
+    NewerSchema {
+        metadata_path: PathBuf,
+        version: u32,
+    },
+    /// The metadata file is larger than the database accepts.
+    Oversized { metadata_path: PathBuf, size: u64 },
+    /// The scheduler type is unknown to this version of astronote.
+    /// The note is kept intact but cannot be reviewed.
+    UnsupportedScheduler {
+        metadata_path: PathBuf,
+        relative_path: PathBuf,
+        scheduler: String,
+    },
+    /// Several notes point at the same file.
+    Duplicate {
+        canonical_path: PathBuf,
+        metadata_pathes: Vec<PathBuf>,
+    },
+}
+
+impl NoteRepository {
+    /// Check every metadata file against the files under `root`.
+    pub fn diagnose(&self, root: &Path) -> Result<Vec<Problem>> {
+        let mut problems = vec![];
+        let mut notes_by_file: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for metadata_path in list_metadata_files(&self.database_dir)? {
+            let size = fs::metadata(&metadata_path)
+                .with_context(|| format!("Failed to read metadata of {metadata_path:?}"))?
+                .len();
+            if size > METADATA_SIZE_LIMIT as u64 {
+                problems.push(Problem::Oversized {
+                    metadata_path,
+                    size,
+                });
+                continue;
+            }
+            let note = read_serialized_metadata(&metadata_path).and_then(deserialize_note);
+            let note = match note {
+                Ok(note) => note,
+                Err(err) => {
+                    let problem = match err.downcast_ref::<migration::NewerSchemaError>() {
+                        Some(newer) => Problem::NewerSchema {
+                            metadata_path,
+                            version: newer.version,
+                        },
+                        None => Problem::Corrupt {
+                            metadata_path,
+                            error: format!("{err:#}"),
+                        },
+                    };
+                    problems.push(problem);
+                    continue;
+                }
+            };
+            if let Some(unsupported) = note.scheduler.unsupported() {
+                problems.push(Problem::UnsupportedScheduler {
+                    metadata_path: metadata_path.clone(),
+                    relative_path: note.relative_path.clone(),
+                    scheduler: unsupported.type_name().to_string(),
+                });
+            }
+            let file_path = root.join(&note.relative_path);
+            match file_path.canonicalize() {
+                Ok(canonical_path) => notes_by_file
+                    .entry(canonical_path)
+                    .or_default()
+                    .push(metadata_path),
+                Err(_) => problems.push(Problem::Orphan {
+                    metadata_path,
+                    relative_path: note.relative_path,
+                }),
+            }
+        }
+        let duplicates = notes_by_file
+            .into_iter()
+            .filter(|(_, metadata_pathes)| metadata_pathes.len() > 1)
+            .map(|(canonical_path, metadata_pathes)| Problem::Duplicate {
+                canonical_path,
+                metadata_pathes,
+            });
+        problems.extend(duplicates);
+        Ok(problems)
+    }
+
+    /// Fix problems found by [`NoteRepository::diagnose`].
+    ///
+    /// Orphans are removed like deleted notes, leaving tombstones for sync.
+    /// Corrupt and oversized files are renamed with a `.broken` suffix so that they are
+    /// no longer loaded but can still be inspected.
+    /// Duplicates are merged like diverged copies in a sync, and kept under the canonical path.
+    pub fn repair(&self, root: &Path, problems: &[Problem]) -> Result<()> {
+        let _lock = self.lock()?;
+        for problem in problems {
+            match problem {
+                Problem::Orphan {
+                    metadata_path,
+                    relative_path,
+                } => remove_metadata_file(metadata_path, relative_path, &self.database_dir)?,
+                Problem::Corrupt { metadata_path, .. }
+                | Problem::Oversized { metadata_path, .. } => {
+                    let mut broken_path = metadata_path.clone().into_os_string();
+                    broken_path.push(".broken");
+                    fs::rename(metadata_path, &broken_path)
+                        .with_context(|| format!("Failed to move {metadata_path:?} aside"))?;
+                }
+                Problem::Duplicate {
+                    canonical_path,
+                    metadata_pathes,
+                } => self.merge_duplicates(root, canonical_path, metadata_pathes)?,
+                // needs a newer astronote rather than a repair
+                Problem::UnsupportedScheduler { .. } | Problem::NewerSchema { .. } => {}
+            }
+        }
+        let index = self.build_index()?;
+        index.save(&self.index_path())
+    }
+
+    fn merge_duplicates(
+        &self,
+        root: &Path,
+        canonical_path: &Path,
+        metadata_pathes: &[PathBuf],
+    ) -> Result<()> {
+        let notes = metadata_pathes
+            .iter()
+            .map(|path| Ok((path, read_serialized_metadata(path)?)))
+            .collect::<Result<Vec<_>>>()?;
+        // keep the reviews of every duplicate
+        let mut merged = notes
+            .iter()
+            .map(|(_, note)| note.clone())
+            .reduce(merge_notes)
+            .expect("duplicates consist of several notes");
+        // store the merged note under the canonical path of its file
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to make path absolute: {root:?}"))?;
+        let relative_path = canonical_path
+            .strip_prefix(&root)
+            .with_context(|| format!("{canonical_path:?} is not under {root:?}"))?;
+        merged.relative_path = relative_path.to_path_buf();
+        write_metadata(&merged, &self.database_dir)?;
+        let merged_path = get_metadata_path_from_path(relative_path, &self.database_dir);
+        for (path, note) in notes {
+            if *path != merged_path {
+                remove_metadata_file(path, &note.relative_path, &self.database_dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReviewLog;
+    use std::fs::remove_dir_all;
+
+    const TMPDIR_PATH: &str = "target/test-doctor";
+
+    #[test]
+    #[cfg(unix)]
+    fn diagnose_and_repair() {
+        let root = PathBuf::from(TMPDIR_PATH).join("root");
+        let database_root = PathBuf::from(TMPDIR_PATH).join("database");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.md"), "a").unwrap();
+        std::os::unix::fs::symlink("a.md", root.join("link.md")).unwrap();
+        let repo = NoteRepository::new(&database_root).unwrap();
+        let notes = ["a.md", "link.md", "missing.md"]
+            .into_iter()
+            .map(Note::new_default)
+            .collect();
+        repo.create(notes).unwrap();
+        // both duplicates have been reviewed
+        for (relative_path, day) in [("a.md", 1), ("link.md", 2)] {
+            let metadata_path =
+                get_metadata_path_from_path(Path::new(relative_path), &database_root);
+            let mut note = read_serialized_metadata(&metadata_path).unwrap();
+            note.reviews.push(ReviewLog {
+                datetime: chrono::NaiveDate::from_ymd_opt(2024, 1, day)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap(),
+                quality: 4,
+            });
+            write_metadata(&note, &database_root).unwrap();
+        }
+        fs::write(database_root.join("corrupt.md.metadata"), "(").unwrap();
+        let oversized = "x".repeat(METADATA_SIZE_LIMIT + 1);
+        fs::write(database_root.join("big.md.metadata"), oversized).unwrap();
+        let newer_path = database_root.join("newer.md.metadata");
+        fs::write(
+            &newer_path,
+            r#"(schema_version: 999, relative_path: "newer.md")"#,
+        )
+        .unwrap();
+
+        let problems = repo.diagnose(&root).unwrap();
+        let count = |f: fn(&Problem) -> bool| problems.iter().filter(|p| f(p)).count();
+        assert_eq!(count(|p| matches!(p, Problem::Orphan { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::Corrupt { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::Oversized { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::Duplicate { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::NewerSchema { .. })), 1);
+
+        repo.repair(&root, &problems).unwrap();
+        // notes of a newer astronote are kept as they are
+        let problems = repo.diagnose(&root).unwrap();
+        assert!(matches!(problems[..], [Problem::NewerSchema { .. }]));
+        assert!(newer_path.exists());
+        // removed notes are not brought back by a sync
+        let tombstones = list_files(&database_root, sync::is_tombstone_file).unwrap();
+        assert!(tombstones.contains(&database_root.join("missing.md.metadata.tombstone")));
+        let notes = repo.get_all_lenient().unwrap().notes;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].relative_path, Path::new("a.md"));
+        let merged = read_serialized_metadata(&database_root.join("a.md.metadata")).unwrap();
+        assert_eq!(merged.reviews.len(), 2);
+        remove_dir_all(TMPDIR_PATH).unwrap();
+    }
+}
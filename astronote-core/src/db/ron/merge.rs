@@ -0,0 +1,141 @@
+//! Merge of diverged metadata, for use as a git merge driver, by sync and by doctor.
+
+use anyhow::{Context, Result};
+
+use super::*;
+use crate::{trim_review_log, ReviewLog};
+
+/// Merge two versions of a metadata file into one.
+///
+/// The state of the more recently reviewed version wins, and the review logs of
+/// both are combined. The common ancestor is not needed since reviews are only ever added.
+/// Files written by older versions of astronote are upgraded first.
+pub fn merge_metadata(ours: &str, theirs: &str) -> Result<String> {
+    let ours = migration::from_ron(ours).with_context(|| "Failed to read our version")?;
+    let theirs = migration::from_ron(theirs).with_context(|| "Failed to read their version")?;
+    to_ron_string(&merge_notes(ours, theirs))
+}
+
+pub(super) fn merge_notes(ours: SerializedNote, theirs: SerializedNote) -> SerializedNote {
+    let revision = ours.revision.max(theirs.revision) + 1;
+    // both share the oldest reviews; the side which dropped more of them from its log
+    // has counted those the other still keeps
+    let (more, fewer) = if theirs.dropped_reviews > ours.dropped_reviews {
+        (&theirs, &ours)
+    } else {
+        (&ours, &theirs)
+    };
+    let oldest_kept = match more.reviews.first() {
+        Some(review) if more.dropped_reviews > 0 => Some(review.datetime),
+        _ => None,
+    };
+    let mut reviews: Vec<ReviewLog> = more
+        .reviews
+        .iter()
+        .chain(
+            fewer
+                .reviews
+                .iter()
+                .filter(|review| oldest_kept.is_none_or(|oldest| review.datetime >= oldest)),
+        )
+        .cloned()
+        .collect();
+    reviews.sort_by_key(|review| review.datetime);
+    reviews.dedup();
+    let dropped_reviews = more.dropped_reviews + trim_review_log(&mut reviews);
+    // on a tie, keep ours like git does
+    let mut merged = if recency(&theirs) > recency(&ours) {
+        theirs
+    } else {
+        ours
+    };
+    merged.revision = revision;
+    merged.reviews = reviews;
+    merged.dropped_reviews = dropped_reviews;
+    merged
+}
+
+// Notes reviewed without a log (written by older versions) fall back to the revision.
+fn recency(note: &SerializedNote) -> (Option<chrono::NaiveDateTime>, u64) {
+    let last_reviewed = note.reviews.iter().map(|review| review.datetime).max();
+    (last_reviewed, note.revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, NaiveDate};
+
+    fn reviewed_at(day: u32, note: &mut SerializedNote) {
+        let datetime = NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        note.reviews.push(ReviewLog {
+            datetime,
+            quality: day as u8,
+        });
+        note.next_datetime = datetime;
+        note.revision += 1;
+    }
+
+    #[test]
+    fn merge_concurrent_reviews() {
+        let mut base = serialize_note(Note::new_default("a.md")).unwrap();
+        reviewed_at(1, &mut base);
+        let mut ours = serialize_note(Note::new_default("a.md")).unwrap();
+        let mut theirs = serialize_note(Note::new_default("a.md")).unwrap();
+        for note in [&mut ours, &mut theirs] {
+            note.reviews = base.reviews.clone();
+            note.revision = base.revision;
+        }
+        reviewed_at(3, &mut ours);
+        reviewed_at(2, &mut theirs);
+        reviewed_at(4, &mut theirs);
+
+        let ours = to_ron_string(&ours).unwrap();
+        let theirs = to_ron_string(&theirs).unwrap();
+        let merged = merge_metadata(&ours, &theirs).unwrap();
+        let merged = migration::from_ron(&merged).unwrap();
+        assert_eq!(merged.next_datetime.day(), 4);
+        let days = merged
+            .reviews
+            .iter()
+            .map(|review| review.datetime.day())
+            .collect::<Vec<_>>();
+        assert_eq!(days, vec![1, 2, 3, 4]);
+        assert_eq!(merged.revision, 4);
+        // merging is symmetric
+        assert_eq!(
+            merge_metadata(&theirs, &ours).unwrap(),
+            to_ron_string(&merged).unwrap()
+        );
+    }
+
+    #[test]
+    fn merge_trimmed_review_logs() {
+        let review = |minute: i64| ReviewLog {
+            datetime: NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                + chrono::Duration::minutes(minute),
+            quality: 4,
+        };
+        let reviewed = |minutes: Vec<i64>| {
+            let mut note = serialize_note(Note::new_default("a.md")).unwrap();
+            note.reviews = minutes.into_iter().map(review).collect();
+            note.dropped_reviews = trim_review_log(&mut note.reviews);
+            note
+        };
+        // both were reviewed 32 times, then once more here and twice more there
+        let ours = reviewed((0..33).collect());
+        let theirs = reviewed((0..32).chain([40, 41]).collect());
+        assert_eq!((ours.dropped_reviews, theirs.dropped_reviews), (1, 2));
+
+        let merged = merge_notes(ours, theirs);
+        assert_eq!(merged.reviews.len(), crate::REVIEW_LOG_LIMIT);
+        assert_eq!(merged.dropped_reviews, 3);
+        assert_eq!(merged.reviews.last(), Some(&review(41)));
+    }
+}
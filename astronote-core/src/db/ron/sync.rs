@@ -0,0 +1,288 @@
+//! Two-way synchronization of RON databases.
+//!
+//! Deleted notes leave a tombstone (`<metadata path>.tombstone`) behind so that
+//! a sync removes them from the other database instead of bringing them back.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::merge::merge_notes;
+use super::*;
+
+const TOMBSTONE_EXTENSION: &str = "tombstone";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Tombstone {
+    #[serde(with = "crate::encoded_path")]
+    relative_path: PathBuf,
+    deleted_at: NaiveDateTime,
+}
+
+/// Result of [`NoteRepository::sync`], listing relative paths of notes.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// notes copied from the other database
+    pub pulled: Vec<PathBuf>,
+    /// notes copied to the other database
+    pub pushed: Vec<PathBuf>,
+    /// notes changed in both databases and merged
+    pub merged: Vec<PathBuf>,
+    /// notes deleted in one database and now removed from the other
+    pub deleted: Vec<PathBuf>,
+    /// files which could not be read or notes which could not be synced
+    pub errors: Vec<(PathBuf, anyhow::Error)>,
+}
+
+// Contents of one of the databases being synced.
+struct Side<'a> {
+    database_dir: &'a Path,
+    notes: BTreeMap<PathBuf, SerializedNote>,
+    tombstones: BTreeMap<PathBuf, Tombstone>,
+}
+
+impl NoteRepository {
+    /// Reconcile this database and `other` note by note, so that both end up the same.
+    ///
+    /// Notes changed in both are merged like [`merge_metadata`] does: the more
+    /// recently reviewed state wins and review logs are combined. A deleted note is
+    /// removed from the other database unless it has been reviewed there since.
+    pub fn sync(&self, other: &NoteRepository) -> Result<SyncReport> {
+        let canonicalize = |dir: &Path| {
+            dir.canonicalize()
+                .with_context(|| format!("Failed to make path absolute: {dir:?}"))
+        };
+        let (this_dir, other_dir) = (
+            canonicalize(&self.database_dir)?,
+            canonicalize(&other.database_dir)?,
+        );
+        if this_dir == other_dir {
+            return Err(anyhow!("Cannot sync {this_dir:?} with itself"));
+        }
+        // lock in a fixed order so that syncs in opposite directions don't deadlock
+        let _locks = if this_dir < other_dir {
+            let this_lock = self.lock()?;
+            (this_lock, other.lock()?)
+        } else {
+            let other_lock = other.lock()?;
+            (self.lock()?, other_lock)
+        };
+
+        let mut report = SyncReport::default();
+        let mut ours = Side::read(&self.database_dir, &mut report.errors)?;
+        let mut theirs = Side::read(&other.database_dir, &mut report.errors)?;
+        let pathes: BTreeSet<PathBuf> = ours
+            .notes
+            .keys()
+            .chain(theirs.notes.keys())
+            .chain(ours.tombstones.keys())
+            .chain(theirs.tombstones.keys())
+            .cloned()
+            .collect();
+        for path in pathes {
+            let result = match (ours.notes.remove(&path), theirs.notes.remove(&path)) {
+                (Some(our_note), Some(their_note)) if our_note != their_note => {
+                    let merged = merge_notes(our_note, their_note);
+                    write_metadata(&merged, ours.database_dir)
+                        .and_then(|_| write_metadata(&merged, theirs.database_dir))
+                        .map(|_| report.merged.push(path.clone()))
+                }
+                (Some(_), Some(_)) => Ok(()),
+                (Some(note), None) => transfer(note, &ours, &theirs).map(|copied| {
+                    let pathes = if copied {
+                        &mut report.pushed
+                    } else {
+                        &mut report.deleted
+                    };
+                    pathes.push(path.clone())
+                }),
+                (None, Some(note)) => transfer(note, &theirs, &ours).map(|copied| {
+                    let pathes = if copied {
+                        &mut report.pulled
+                    } else {
+                        &mut report.deleted
+                    };
+                    pathes.push(path.clone())
+                }),
+                (None, None) => propagate_tombstone(&path, &ours, &theirs)
+                    .and_then(|_| propagate_tombstone(&path, &theirs, &ours)),
+            };
+            if let Err(err) = result {
+                report.errors.push((path, err));
+            }
+        }
+        for repo in [self, other] {
+            repo.build_index()?.save(&repo.index_path())?;
+        }
+        Ok(report)
+    }
+}
+
+impl<'a> Side<'a> {
+    // Unreadable files are reported and left out.
+    fn read(database_dir: &'a Path, errors: &mut Vec<(PathBuf, anyhow::Error)>) -> Result<Self> {
+        let mut notes = BTreeMap::new();
+        for metadata_path in list_metadata_files(database_dir)? {
+            match read_serialized_metadata(&metadata_path) {
+                Ok(note) => {
+                    notes.insert(note.relative_path.clone(), note);
+                }
+                Err(err) => errors.push((metadata_path, err)),
+            }
+        }
+        let mut tombstones = BTreeMap::new();
+        for tombstone_path in list_files(database_dir, is_tombstone_file)? {
+            match read_tombstone(&tombstone_path) {
+                Ok(tombstone) => {
+                    tombstones.insert(tombstone.relative_path.clone(), tombstone);
+                }
+                Err(err) => errors.push((tombstone_path, err)),
+            }
+        }
+        Ok(Self {
+            database_dir,
+            notes,
+            tombstones,
+        })
+    }
+}
+
+// Bring a note which only `from` has to `to`, or delete it from `from` if `to`
+// deleted it after its last review. Returns whether the note was copied.
+fn transfer(note: SerializedNote, from: &Side, to: &Side) -> Result<bool> {
+    if let Some(tombstone) = to.tombstones.get(&note.relative_path) {
+        let reviewed_since = note
+            .reviews
+            .iter()
+            .any(|review| review.datetime > tombstone.deleted_at);
+        if !reviewed_since {
+            let metadata_path = get_metadata_path_from_path(&note.relative_path, from.database_dir);
+            fs::remove_file(&metadata_path)
+                .with_context(|| format!("Failed to remove metadata file: {metadata_path:?}"))?;
+            save_tombstone(tombstone, from.database_dir)?;
+            return Ok(false);
+        }
+    }
+    let metadata_path = get_metadata_path_from_path(&note.relative_path, to.database_dir);
+    // it exists but could not be read; don't overwrite it
+    if metadata_path.exists() {
+        return Err(anyhow!(
+            "{metadata_path:?} is broken; run `astronote doctor`"
+        ));
+    }
+    write_metadata(&note, to.database_dir)?;
+    Ok(true)
+}
+
+fn propagate_tombstone(relative_path: &Path, from: &Side, to: &Side) -> Result<()> {
+    match (
+        from.tombstones.get(relative_path),
+        to.tombstones.get(relative_path),
+    ) {
+        (Some(tombstone), None) => save_tombstone(tombstone, to.database_dir),
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn write_tombstone(relative_path: &Path, database_root: &Path) -> Result<()> {
+    let tombstone = Tombstone {
+        relative_path: relative_path.to_path_buf(),
+        deleted_at: chrono::Local::now().naive_local(),
+    };
+    save_tombstone(&tombstone, database_root)
+}
+
+pub(super) fn remove_tombstone(relative_path: &Path, database_root: &Path) -> Result<()> {
+    let tombstone_path = get_tombstone_path(relative_path, database_root);
+    if tombstone_path.exists() {
+        fs::remove_file(&tombstone_path)
+            .with_context(|| format!("Failed to remove tombstone: {tombstone_path:?}"))?;
+    }
+    Ok(())
+}
+
+fn save_tombstone(tombstone: &Tombstone, database_root: &Path) -> Result<()> {
+    let tombstone_path = get_tombstone_path(&tombstone.relative_path, database_root);
+    if let Some(parent) = tombstone_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+    }
+    let ron = ron::ser::to_string(tombstone).with_context(|| "Failed to serialize tombstone")?;
+    write_atomically(&tombstone_path, ron.as_bytes())
+}
+
+fn read_tombstone(path: &Path) -> Result<Tombstone> {
+    let ron_string = read_metadata_string(path)?;
+    ron::from_str(&ron_string).with_context(|| format!("Failed to parse tombstone: {path:?}"))
+}
+
+fn get_tombstone_path(relative_path: &Path, database_root: &Path) -> PathBuf {
+    let mut path = get_metadata_path_from_path(relative_path, database_root).into_os_string();
+    path.push(".");
+    path.push(TOMBSTONE_EXTENSION);
+    PathBuf::from(path)
+}
+
+pub(super) fn is_tombstone_file(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|ext| ext == TOMBSTONE_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    const TMPDIR_PATH: &str = "target/test-sync";
+
+    #[test]
+    fn sync_databases() {
+        let laptop = NoteRepository::new(&PathBuf::from(TMPDIR_PATH).join("laptop")).unwrap();
+        let desktop = NoteRepository::new(&PathBuf::from(TMPDIR_PATH).join("desktop")).unwrap();
+        let notes = || {
+            ["a.md", "b.md"]
+                .into_iter()
+                .map(Note::new_default)
+                .collect()
+        };
+        laptop.create(notes()).unwrap();
+        desktop.create(notes()).unwrap();
+        desktop.create(vec![Note::new_default("c.md")]).unwrap();
+        // review `a.md` on both machines, and delete `b.md` on the desktop
+        let mut note = laptop.get_one(Path::new("a.md")).unwrap();
+        note.review(3);
+        laptop.update(vec![note]).unwrap();
+        let mut note = desktop.get_one(Path::new("a.md")).unwrap();
+        note.review(5);
+        desktop.update(vec![note]).unwrap();
+        desktop
+            .delete(vec![desktop.get_one(Path::new("b.md")).unwrap()])
+            .unwrap();
+
+        let report = laptop.sync(&desktop).unwrap();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.merged, [Path::new("a.md")]);
+        assert_eq!(report.deleted, [Path::new("b.md")]);
+        assert_eq!(report.pulled, [Path::new("c.md")]);
+        for repo in [&laptop, &desktop] {
+            let mut notes = repo.get_all().unwrap();
+            notes.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+            let pathes = notes
+                .iter()
+                .map(|note| note.relative_path.to_str().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(pathes, vec!["a.md", "c.md"]);
+            assert_eq!(notes[0].reviews.len(), 2);
+        }
+        // nothing left to do
+        let report = desktop.sync(&laptop).unwrap();
+        let changes = [report.pulled, report.pushed, report.merged, report.deleted];
+        assert!(changes.iter().all(|pathes| pathes.is_empty()));
+        remove_dir_all(TMPDIR_PATH).unwrap();
+    }
+}
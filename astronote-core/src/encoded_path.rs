@@ -0,0 +1,96 @@
+//! Lossless serialization of note paths.
+//!
+//! Paths which are valid UTF-8 are written as plain strings, as they always were.
+//! Any other path, e.g. a Latin-1 file name from a legacy system, is written as its
+//! raw bytes tagged with `bytes`, so that it is read back exactly.
+//!
+//! Use it as `#[serde(with = "crate::encoded_path")]` on `PathBuf` fields.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EncodedPath {
+    Utf8(String),
+    Bytes { bytes: Vec<u8> },
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    let encoded = match path.to_str() {
+        Some(path) => EncodedPath::Utf8(path.to_string()),
+        None => EncodedPath::Bytes {
+            bytes: to_bytes(path),
+        },
+    };
+    encoded.serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    let path = match EncodedPath::deserialize(deserializer)? {
+        EncodedPath::Utf8(path) => PathBuf::from(path),
+        EncodedPath::Bytes { bytes } => from_bytes(bytes),
+    };
+    Ok(path)
+}
+
+/// Raw bytes of a path, e.g. to use it as a key.
+pub fn to_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    // paths are Unicode elsewhere, apart from unpaired surrogates on Windows
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().as_bytes().to_vec()
+    }
+}
+
+/// Inverse of [`to_bytes`].
+pub fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Entry {
+        #[serde(with = "super")]
+        relative_path: PathBuf,
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn round_trip_non_utf8_path() {
+        let utf8 = Entry {
+            relative_path: PathBuf::from("notes/a.md"),
+        };
+        // "café.md" in Latin-1
+        let latin1 = Entry {
+            relative_path: from_bytes(b"notes/caf\xe9.md".to_vec()),
+        };
+        assert!(latin1.relative_path.to_str().is_none());
+
+        // UTF-8 paths are written as before
+        let ron_string = ron::to_string(&utf8).unwrap();
+        assert_eq!(ron_string, r#"(relative_path:"notes/a.md")"#);
+        for entry in [utf8, latin1] {
+            let ron_string = ron::to_string(&entry).unwrap();
+            assert_eq!(ron::from_str::<Entry>(&ron_string).unwrap(), entry);
+            let json = serde_json::to_string(&entry).unwrap();
+            assert_eq!(serde_json::from_str::<Entry>(&json).unwrap(), entry);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Identify file contents independently of their path.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// SHA-256 of the file content as a lowercase hex string.
+pub fn fingerprint(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {path:?}"))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Inode number of the file, if the platform has one.
+pub fn inode(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).ok().map(|metadata| metadata.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, remove_dir_all};
+
+    #[test]
+    fn fingerprint_follows_content() {
+        let dir = Path::new("target/test-fingerprint");
+        fs::create_dir_all(dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "content").unwrap();
+        fs::write(&b, "content").unwrap();
+        assert_eq!(fingerprint(&a).unwrap(), fingerprint(&b).unwrap());
+        fs::write(&b, "changed").unwrap();
+        assert_ne!(fingerprint(&a).unwrap(), fingerprint(&b).unwrap());
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,58 +1,142 @@
 pub mod db;
+pub mod encoded_path;
+pub mod fingerprint;
+pub mod migration;
 pub mod schedulers;
 
 pub mod prelude {
     pub use crate::schedulers::SchedulingAlgorithm;
     pub use crate::Note;
+    pub use crate::ReviewLog;
     pub use crate::SerializedNote;
 }
 
-use schedulers::{sm2::SuperMemo2, SchedulingAlgorithm};
+use anyhow::Result;
+use schedulers::{sm2::SuperMemo2, unsupported::Unsupported, SchedulingAlgorithm, SCHEDULER_TYPES};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Note {
-    pub relative_path: String,
+    /// path of the file relative to the root; need not be valid UTF-8
+    #[serde(with = "encoded_path")]
+    pub relative_path: PathBuf,
     pub next_datetime: chrono::NaiveDateTime,
     pub scheduler: Box<dyn SchedulingAlgorithm>,
+    /// incremented on every update; used to detect concurrent modification
+    pub revision: u64,
+    /// fingerprint of the file content; used to find the file after it was moved
+    pub fingerprint: Option<String>,
+    pub inode: Option<u64>,
+    /// latest reviews of the note, the oldest first; see [`REVIEW_LOG_LIMIT`]
+    pub reviews: Vec<ReviewLog>,
+    /// number of older reviews dropped from `reviews`
+    pub dropped_reviews: u64,
+}
+
+/// Number of the latest reviews kept in the log of a note, so that its metadata stays small.
+pub const REVIEW_LOG_LIMIT: usize = 32;
+
+/// A single review of a note.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewLog {
+    pub datetime: chrono::NaiveDateTime,
+    /// quality of answer given by the user
+    pub quality: u8,
 }
 
 impl Note {
     pub fn new(
-        relative_path: &str,
+        relative_path: impl AsRef<Path>,
         next_datetime: &chrono::NaiveDateTime,
         scheduler: Box<dyn SchedulingAlgorithm>,
     ) -> Self {
         Self {
-            relative_path: String::from(relative_path),
+            relative_path: relative_path.as_ref().to_path_buf(),
             next_datetime: *next_datetime,
             scheduler,
+            revision: 0,
+            fingerprint: None,
+            inode: None,
+            reviews: vec![],
+            dropped_reviews: 0,
         }
     }
 
-    pub fn new_default(relative_path: &str) -> Self {
+    pub fn new_default(relative_path: impl AsRef<Path>) -> Self {
         let now = chrono::Local::now().naive_local();
         let sm2 = Box::<SuperMemo2>::default();
         Self::new(relative_path, &now, sm2)
     }
+
+    /// Grade the note: update its schedule and log the review.
+    pub fn review(&mut self, quality: u8) {
+        self.next_datetime = self.scheduler.update_and_calculate_next_datetime(quality);
+        self.reviews.push(ReviewLog {
+            datetime: chrono::Local::now().naive_local(),
+            quality,
+        });
+        self.dropped_reviews += trim_review_log(&mut self.reviews);
+    }
+
+    /// Number of reviews so far, including those dropped from the log.
+    pub fn review_count(&self) -> u64 {
+        self.dropped_reviews + self.reviews.len() as u64
+    }
+
+    /// Record the fingerprint and inode of the note's file at `path`.
+    pub fn refresh_fingerprint(&mut self, path: &Path) -> Result<()> {
+        self.fingerprint = Some(fingerprint::fingerprint(path)?);
+        self.inode = fingerprint::inode(path);
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SerializedNote {
-    pub relative_path: String,
+    pub schema_version: u32,
+    #[serde(with = "encoded_path")]
+    pub relative_path: PathBuf,
     pub next_datetime: chrono::NaiveDateTime,
     pub scheduler: serde_json::Value,
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub inode: Option<u64>,
+    #[serde(default)]
+    pub reviews: Vec<ReviewLog>,
+    #[serde(default)]
+    pub dropped_reviews: u64,
+}
+
+// Drop the oldest reviews beyond `REVIEW_LOG_LIMIT`; returns how many were dropped.
+pub(crate) fn trim_review_log(reviews: &mut Vec<ReviewLog>) -> u64 {
+    let excess = reviews.len().saturating_sub(REVIEW_LOG_LIMIT);
+    reviews.drain(..excess);
+    excess as u64
 }
 
 impl TryFrom<Note> for SerializedNote {
     type Error = serde_json::Error;
 
     fn try_from(value: Note) -> Result<Self, Self::Error> {
-        let serialized_scheduler = serde_json::to_value(value.scheduler)?;
+        // unknown schedulers are written back exactly as they were read
+        let serialized_scheduler = match value.scheduler.unsupported() {
+            Some(unsupported) => unsupported.raw.clone(),
+            None => serde_json::to_value(value.scheduler)?,
+        };
         Ok(SerializedNote {
+            schema_version: migration::SCHEMA_VERSION,
             relative_path: value.relative_path,
             next_datetime: value.next_datetime,
             scheduler: serialized_scheduler,
+            revision: value.revision,
+            fingerprint: value.fingerprint,
+            inode: value.inode,
+            reviews: value.reviews,
+            dropped_reviews: value.dropped_reviews,
         })
     }
 }
@@ -61,12 +145,23 @@ impl TryInto<Note> for SerializedNote {
     type Error = serde_json::Error;
 
     fn try_into(self) -> Result<Note, Self::Error> {
-        let deserialized_scheduler: Box<dyn SchedulingAlgorithm> =
-            serde_json::from_value(self.scheduler)?;
+        // a known scheduler which fails to deserialize is an error, not an unknown one
+        let type_name = self.scheduler.get("type").and_then(|name| name.as_str());
+        let deserialized_scheduler: Box<dyn SchedulingAlgorithm> = match type_name {
+            Some(type_name) if !SCHEDULER_TYPES.contains(&type_name) => {
+                Box::new(Unsupported::new(self.scheduler))
+            }
+            _ => serde_json::from_value(self.scheduler)?,
+        };
         Ok(Note {
             relative_path: self.relative_path,
             next_datetime: self.next_datetime,
             scheduler: deserialized_scheduler,
+            revision: self.revision,
+            fingerprint: self.fingerprint,
+            inode: self.inode,
+            reviews: self.reviews,
+            dropped_reviews: self.dropped_reviews,
         })
     }
 }
@@ -82,16 +177,75 @@ mod tests {
     fn serialize_note() {
         let mut rng = rand::thread_rng();
         let note = Note {
-            relative_path: String::from("test"),
+            relative_path: PathBuf::from("test"),
             next_datetime: NaiveDateTime::default(),
             scheduler: Box::new(SuperMemo2::new(
                 rng.gen_range(0..10),
                 rng.gen_range(0..10),
                 rng.gen_range(0.0..5.0),
             )),
+            revision: 0,
+            fingerprint: None,
+            inode: None,
+            reviews: vec![],
+            dropped_reviews: 0,
         };
 
         let serialized_note: SerializedNote = note.try_into().unwrap();
         let _deserialized_note: Note = serialized_note.try_into().unwrap();
     }
+
+    #[test]
+    fn cap_review_log() {
+        let mut note = Note::new_default("test");
+        // failed reviews keep the intervals short
+        for _ in 0..100 {
+            note.review(0);
+        }
+        assert_eq!(note.reviews.len(), REVIEW_LOG_LIMIT);
+        assert_eq!(note.review_count(), 100);
+    }
+
+    #[test]
+    fn keep_unknown_scheduler() {
+        let scheduler = serde_json::json!({ "type": "Unknown", "stability": 1.5 });
+        let serialized_note = SerializedNote {
+            schema_version: migration::SCHEMA_VERSION,
+            relative_path: PathBuf::from("test"),
+            next_datetime: NaiveDateTime::default(),
+            scheduler: scheduler.clone(),
+            revision: 0,
+            fingerprint: None,
+            inode: None,
+            reviews: vec![],
+            dropped_reviews: 0,
+        };
+
+        let note: Note = serialized_note.try_into().unwrap();
+        let unsupported = note.scheduler.unsupported().unwrap();
+        assert_eq!(unsupported.type_name(), "Unknown");
+        let serialized_note: SerializedNote = note.try_into().unwrap();
+        assert_eq!(serialized_note.scheduler, scheduler);
+
+        // a known scheduler with broken fields is not mistaken for an unknown one
+        let broken = SerializedNote {
+            scheduler: serde_json::json!({ "type": "SuperMemo2", "counter": "one" }),
+            ..serialized_note
+        };
+        assert!(TryInto::<Note>::try_into(broken).is_err());
+    }
+
+    #[test]
+    fn deserialize_registered_schedulers() {
+        for type_name in SCHEDULER_TYPES {
+            let scheduler: Box<dyn SchedulingAlgorithm> = match *type_name {
+                "SuperMemo2" => Box::<SuperMemo2>::default(),
+                _ => unreachable!("add a default scheduler for {type_name}"),
+            };
+            let value = serde_json::to_value(&scheduler).unwrap();
+            assert_eq!(value["type"], *type_name);
+            let scheduler: Box<dyn SchedulingAlgorithm> = serde_json::from_value(value).unwrap();
+            assert!(scheduler.unsupported().is_none());
+        }
+    }
 }
@@ -0,0 +1,278 @@
+//! Upgrade metadata written by older versions of astronote.
+//!
+//! Every serialized note carries the schema version it was written with.
+//! When reading, the version is probed first and the note is deserialized with the
+//! structure of that version, then upgraded step by step to the current one.
+//!
+//! To change the format, freeze the current [`SerializedNote`] as a new `vN` module,
+//! bump [`SCHEMA_VERSION`] and add the upgrade step from `vN`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+
+use crate::SerializedNote;
+
+/// Schema version written by this version of astronote.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Returned for metadata written by a newer version of astronote.
+///
+/// Such a note is not broken; it must be kept as it is for the newer version.
+#[derive(Debug)]
+pub struct NewerSchemaError {
+    pub version: u32,
+}
+
+impl fmt::Display for NewerSchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Metadata has schema version {}, but this astronote supports up to {SCHEMA_VERSION}; upgrade astronote",
+            self.version
+        )
+    }
+}
+
+impl std::error::Error for NewerSchemaError {}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    // notes written before versioning have no version
+    #[serde(default)]
+    schema_version: u32,
+}
+
+/// Schema version of a RON metadata string.
+pub fn schema_version_of_ron(ron_string: &str) -> Result<u32> {
+    let probe: VersionProbe =
+        ron::from_str(ron_string).with_context(|| "Failed to read schema version")?;
+    Ok(probe.schema_version)
+}
+
+/// Deserialize RON metadata of any known schema version into the current one.
+pub fn from_ron(ron_string: &str) -> Result<SerializedNote> {
+    let serialized_note = match schema_version_of_ron(ron_string)? {
+        0 => {
+            let v1 = v1::SerializedNote::from(ron::from_str::<v0::SerializedNote>(ron_string)?);
+            v3::SerializedNote::from(v2::SerializedNote::from(v1)).into()
+        }
+        1 => {
+            let v2 = v2::SerializedNote::from(ron::from_str::<v1::SerializedNote>(ron_string)?);
+            v3::SerializedNote::from(v2).into()
+        }
+        2 => v3::SerializedNote::from(ron::from_str::<v2::SerializedNote>(ron_string)?).into(),
+        3 => ron::from_str::<v3::SerializedNote>(ron_string)?.into(),
+        SCHEMA_VERSION => ron::from_str(ron_string)?,
+        version => return Err(NewerSchemaError { version }.into()),
+    };
+    Ok(serialized_note)
+}
+
+/// Notes written before schema versioning.
+mod v0 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct SerializedNote {
+        pub relative_path: String,
+        pub next_datetime: chrono::NaiveDateTime,
+        pub scheduler: serde_json::Value,
+        #[serde(default)]
+        pub revision: u64,
+        #[serde(default)]
+        pub fingerprint: Option<String>,
+        #[serde(default)]
+        pub inode: Option<u64>,
+    }
+
+    impl From<SerializedNote> for super::v1::SerializedNote {
+        fn from(value: SerializedNote) -> Self {
+            Self {
+                schema_version: 1,
+                relative_path: value.relative_path,
+                next_datetime: value.next_datetime,
+                scheduler: value.scheduler,
+                revision: value.revision,
+                fingerprint: value.fingerprint,
+                inode: value.inode,
+            }
+        }
+    }
+}
+
+/// Notes without review logs.
+mod v1 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct SerializedNote {
+        pub schema_version: u32,
+        pub relative_path: String,
+        pub next_datetime: chrono::NaiveDateTime,
+        pub scheduler: serde_json::Value,
+        pub revision: u64,
+        pub fingerprint: Option<String>,
+        pub inode: Option<u64>,
+    }
+
+    impl From<SerializedNote> for super::v2::SerializedNote {
+        fn from(value: SerializedNote) -> Self {
+            Self {
+                schema_version: 2,
+                relative_path: value.relative_path,
+                next_datetime: value.next_datetime,
+                scheduler: value.scheduler,
+                revision: value.revision,
+                fingerprint: value.fingerprint,
+                inode: value.inode,
+                reviews: vec![],
+            }
+        }
+    }
+}
+
+/// Notes whose paths are always UTF-8 strings.
+mod v2 {
+    use serde::Deserialize;
+
+    use crate::ReviewLog;
+
+    #[derive(Deserialize)]
+    pub struct SerializedNote {
+        pub schema_version: u32,
+        pub relative_path: String,
+        pub next_datetime: chrono::NaiveDateTime,
+        pub scheduler: serde_json::Value,
+        #[serde(default)]
+        pub revision: u64,
+        #[serde(default)]
+        pub fingerprint: Option<String>,
+        #[serde(default)]
+        pub inode: Option<u64>,
+        #[serde(default)]
+        pub reviews: Vec<ReviewLog>,
+    }
+
+    impl From<SerializedNote> for super::v3::SerializedNote {
+        fn from(value: SerializedNote) -> Self {
+            Self {
+                schema_version: 3,
+                relative_path: value.relative_path.into(),
+                next_datetime: value.next_datetime,
+                scheduler: value.scheduler,
+                revision: value.revision,
+                fingerprint: value.fingerprint,
+                inode: value.inode,
+                reviews: value.reviews,
+            }
+        }
+    }
+}
+
+/// Notes which keep every review in their log.
+mod v3 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    use crate::{trim_review_log, ReviewLog};
+
+    #[derive(Deserialize)]
+    pub struct SerializedNote {
+        pub schema_version: u32,
+        #[serde(with = "crate::encoded_path")]
+        pub relative_path: PathBuf,
+        pub next_datetime: chrono::NaiveDateTime,
+        pub scheduler: serde_json::Value,
+        #[serde(default)]
+        pub revision: u64,
+        #[serde(default)]
+        pub fingerprint: Option<String>,
+        #[serde(default)]
+        pub inode: Option<u64>,
+        #[serde(default)]
+        pub reviews: Vec<ReviewLog>,
+    }
+
+    impl From<SerializedNote> for crate::SerializedNote {
+        fn from(mut value: SerializedNote) -> Self {
+            let dropped_reviews = trim_review_log(&mut value.reviews);
+            Self {
+                schema_version: 4,
+                relative_path: value.relative_path,
+                next_datetime: value.next_datetime,
+                scheduler: value.scheduler,
+                revision: value.revision,
+                fingerprint: value.fingerprint,
+                inode: value.inode,
+                reviews: value.reviews,
+                dropped_reviews,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_unversioned_note() {
+        let ron_string = r#"(
+            relative_path: "a.md",
+            next_datetime: "2023-10-01T12:00:00",
+            scheduler: {
+                "counter": 0,
+                "easiness_factor": 2.5,
+                "interval": 0,
+                "type": "SuperMemo2",
+            },
+        )"#;
+        assert_eq!(schema_version_of_ron(ron_string).unwrap(), 0);
+        let serialized_note = from_ron(ron_string).unwrap();
+        assert_eq!(serialized_note.schema_version, SCHEMA_VERSION);
+        assert_eq!(serialized_note.relative_path, std::path::Path::new("a.md"));
+        assert_eq!(serialized_note.revision, 0);
+        assert!(serialized_note.reviews.is_empty());
+    }
+
+    #[test]
+    fn upgrade_note_with_string_path() {
+        let ron_string = r#"(
+            schema_version: 2,
+            relative_path: "a.md",
+            next_datetime: "2023-10-01T12:00:00",
+            scheduler: { "type": "SuperMemo2" },
+            reviews: [(datetime: "2023-09-30T12:00:00", quality: 4)],
+        )"#;
+        let serialized_note = from_ron(ron_string).unwrap();
+        assert_eq!(serialized_note.schema_version, SCHEMA_VERSION);
+        assert_eq!(serialized_note.relative_path, std::path::Path::new("a.md"));
+        assert_eq!(serialized_note.reviews.len(), 1);
+    }
+
+    #[test]
+    fn trim_long_review_log() {
+        let reviews = vec!["(datetime: \"2023-09-30T12:00:00\", quality: 4)"; 40].join(", ");
+        let ron_string = format!(
+            r#"(
+            schema_version: 3,
+            relative_path: "a.md",
+            next_datetime: "2023-10-01T12:00:00",
+            scheduler: {{ "type": "SuperMemo2" }},
+            reviews: [{reviews}],
+        )"#
+        );
+        let serialized_note = from_ron(&ron_string).unwrap();
+        assert_eq!(serialized_note.schema_version, SCHEMA_VERSION);
+        assert_eq!(serialized_note.reviews.len(), crate::REVIEW_LOG_LIMIT);
+        assert_eq!(serialized_note.dropped_reviews, 8);
+    }
+
+    #[test]
+    fn reject_newer_schema() {
+        let ron_string = r#"(schema_version: 999, relative_path: "a.md")"#;
+        let err = from_ron(ron_string).unwrap_err();
+        assert_eq!(err.downcast_ref::<NewerSchemaError>().unwrap().version, 999);
+    }
+}
@@ -1,9 +1,21 @@
 pub mod sm2;
+pub mod unsupported;
 
 use std::fmt::Debug;
+use unsupported::Unsupported;
+
+/// `type` tags of the schedulers this version of astronote can deserialize.
+///
+/// Keep in sync with the `#[typetag::serde]` implementations; any other tag is
+/// read as [`Unsupported`].
+pub const SCHEDULER_TYPES: &[&str] = &["SuperMemo2"];
 
 #[typetag::serde(tag = "type")]
 pub trait SchedulingAlgorithm: Debug + Send + Sync {
     fn update_and_calculate_next_datetime(&mut self, response: u8) -> chrono::NaiveDateTime;
     fn calculate_next_datetime(&self, response: u8) -> chrono::NaiveDateTime;
+    /// `Some` if the scheduler type is unknown to this version of astronote.
+    fn unsupported(&self) -> Option<&Unsupported> {
+        None
+    }
 }
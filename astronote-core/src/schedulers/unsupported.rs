@@ -0,0 +1,40 @@
+use crate::schedulers::SchedulingAlgorithm;
+use serde::{Deserialize, Serialize};
+
+/// Scheduler of a type this version of astronote does not know,
+/// e.g. written by a newer or custom build.
+///
+/// The serialized scheduler is kept as is, so that writing the note back
+/// does not lose its state. Such notes cannot be reviewed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Unsupported {
+    pub raw: serde_json::Value,
+}
+
+impl Unsupported {
+    pub fn new(raw: serde_json::Value) -> Self {
+        Self { raw }
+    }
+
+    /// The `type` tag of the unknown scheduler.
+    pub fn type_name(&self) -> &str {
+        self.raw
+            .get("type")
+            .and_then(|name| name.as_str())
+            .unwrap_or_default()
+    }
+}
+
+#[typetag::serde(name = "astronote::Unsupported")]
+impl SchedulingAlgorithm for Unsupported {
+    // The schedule cannot be calculated; the note keeps being due "never".
+    fn update_and_calculate_next_datetime(&mut self, _response: u8) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::MAX
+    }
+    fn calculate_next_datetime(&self, _response: u8) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::MAX
+    }
+    fn unsupported(&self) -> Option<&Unsupported> {
+        Some(self)
+    }
+}